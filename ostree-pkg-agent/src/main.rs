@@ -0,0 +1,638 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, create_dir_all, File};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "Usage: ostree-pkg-agent <plan or directory>...
+
+Runs the plans ostree-pkg writes to /usr/share/ostree-pkg/plans.d/ and records
+their outcome for ostree-pkg-status.";
+
+/// A boot module as written by the ostree-pkg plugin.
+#[derive(Deserialize)]
+struct Plan {
+    module: String,
+    unit: String,
+    scope: String,
+    manager: String,
+    action: String,
+    packages: Vec<String>,
+
+    /// Hardware the plan is skipped without.
+    #[serde(default)]
+    conditions: Option<Conditions>,
+
+    /// Variables set for every command.
+    #[serde(default)]
+    env: Vec<Var>,
+
+    /// Shell commands run before the manager.
+    #[serde(default)]
+    before: Vec<String>,
+
+    #[serde(default)]
+    flags: Vec<String>,
+
+    /// Packages, refs or files the manager is given.
+    targets: Vec<String>,
+
+    /// Shell commands run after the manager.
+    #[serde(default)]
+    after: Vec<String>,
+
+    #[serde(default)]
+    retry: Option<Retry>,
+}
+
+/// PCI vendor ids such as `0x10de`, one of which a device under `sysfs_root`
+/// must have.
+#[derive(Deserialize)]
+struct Conditions {
+    pci_vendor: Vec<String>,
+    sysfs_root: PathBuf,
+}
+
+/// An environment variable, set to `value` or to the secret in the systemd
+/// credential named `credential`.
+#[derive(Deserialize)]
+struct Var {
+    name: String,
+
+    #[serde(flatten)]
+    source: Source,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Deserialize)]
+enum Source {
+    value(String),
+    credential(String),
+}
+
+/// How a failed plan is retried, with the same meaning as the plugin's
+/// `retry` setting.
+#[derive(Deserialize)]
+#[serde(default)]
+struct Retry {
+    attempts: u32,
+    delay: u64,
+    backoff: u64,
+    jitter: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 5,
+            delay: 30,
+            backoff: 2,
+            jitter: 0,
+        }
+    }
+}
+
+/// The outcome of the last run of a plan, read by `ostree-pkg-status`.
+#[derive(Serialize)]
+struct Status<'a> {
+    module: &'a str,
+    unit: &'a str,
+    scope: &'a str,
+    manager: &'a str,
+    action: &'a str,
+    packages: &'a [String],
+    time: String,
+    exit_code: i32,
+    attempts: u32,
+}
+
+/// Where a plan takes its lock and records its outcome.
+struct Dirs {
+    lock: PathBuf,
+    status: PathBuf,
+    failed: PathBuf,
+}
+
+impl Dirs {
+    fn for_scope(scope: &str) -> Dirs {
+        match scope {
+            "user" => {
+                let state = env::var_os("XDG_STATE_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+                    .unwrap_or_else(env::temp_dir);
+                let runtime = env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).unwrap_or_else(env::temp_dir);
+
+                Dirs {
+                    lock: runtime.join("ostree-pkg-agent.lock"),
+                    status: state.join("ostree-pkg/status"),
+                    failed: state.join("ostree-pkg/failed"),
+                }
+            }
+            _ => Dirs {
+                lock: PathBuf::from("/run/ostree-pkg/agent.lock"),
+                status: PathBuf::from("/var/lib/ostree-pkg/status"),
+                failed: PathBuf::from("/var/lib/ostree-pkg/failed"),
+            },
+        }
+    }
+}
+
+fn log(plan: &Plan, message: &str) {
+    eprintln!("{} ({}): {message}", plan.unit, plan.module);
+}
+
+/// The current time as `date -Iseconds` prints it in UTC.
+fn now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    timestamp(secs)
+}
+
+fn timestamp(secs: u64) -> String {
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}+00:00",
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+/// Up to `max` seconds, so that many machines booting at once don't retry in
+/// lockstep.
+fn jitter(max: u64) -> u64 {
+    match max {
+        0 => 0,
+        max => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos() as u64 % (max + 1),
+    }
+}
+
+/// The manager's command line for `plan`, built from its fields rather than
+/// run through a shell.
+fn argv(plan: &Plan) -> Result<Vec<String>, String> {
+    let action: &[&str] = match (plan.manager.as_str(), plan.action.as_str()) {
+        ("dnf" | "dnf5", "install") => &["install", "-y"],
+        ("dnf" | "dnf5", "uninstall") => &["uninstall", "-y"],
+        ("rpm-ostree", "install") => &["install", "-y", "--idempotent"],
+        ("rpm-ostree", "uninstall") => &["uninstall", "-y", "--idempotent"],
+        ("flatpak", "install") => &["install", "--noninteractive"],
+        ("flatpak", "uninstall") => &["uninstall", "--noninteractive"],
+        (manager, action) => return Err(format!("Error: {action} is not supported on {manager}")),
+    };
+
+    Ok(std::iter::once(plan.manager.clone())
+        .chain(action.iter().map(|arg| arg.to_string()))
+        .chain(plan.flags.iter().cloned())
+        .chain(plan.targets.iter().cloned())
+        .collect())
+}
+
+/// Whether a PCI device from one of the vendors is present.
+fn conditions_met(conditions: &Conditions) -> bool {
+    let Ok(devices) = fs::read_dir(conditions.sysfs_root.join("bus/pci/devices")) else {
+        return false;
+    };
+
+    devices.filter_map(Result::ok).any(|device| {
+        fs::read_to_string(device.path().join("vendor"))
+            .is_ok_and(|vendor| conditions.pci_vendor.contains(&vendor.trim().to_lowercase()))
+    })
+}
+
+/// The values of the variables of `plan`, with credentials read from the
+/// directory systemd passes them in.
+fn vars(plan: &Plan) -> Result<Vec<(String, String)>, String> {
+    plan.env
+        .iter()
+        .map(|var| match &var.source {
+            Source::value(value) => Ok((var.name.clone(), value.clone())),
+            Source::credential(credential) => {
+                let dir = env::var_os("CREDENTIALS_DIRECTORY")
+                    .ok_or_else(|| format!("Error: credential {credential} needs $CREDENTIALS_DIRECTORY"))?;
+                let path = Path::new(&dir).join(credential);
+                let secret = fs::read_to_string(&path).map_err(|e| format!("Error reading {}: {e}", path.display()))?;
+
+                // as the shell's $(cat ...) would
+                Ok((var.name.clone(), secret.trim_end_matches('\n').to_string()))
+            }
+        })
+        .collect()
+}
+
+fn sh(command: &str) -> Command {
+    let mut sh = Command::new("sh");
+    sh.arg("-c").arg(command);
+    sh
+}
+
+/// Runs `before`, the manager and `after` once with `vars` set, returning the
+/// exit code of the first that fails.
+fn attempt(plan: &Plan, argv: &[String], vars: &[(String, String)]) -> i32 {
+    let mut manager = Command::new(&argv[0]);
+    manager.args(&argv[1..]);
+
+    let commands = plan
+        .before
+        .iter()
+        .map(|step| sh(step))
+        .chain(Some(manager))
+        .chain(plan.after.iter().map(|step| sh(step)));
+
+    for mut command in commands {
+        let code = match command.envs(vars.iter().cloned()).status() {
+            Ok(status) => status.code().unwrap_or(1),
+            Err(e) => {
+                log(plan, &format!("Error running {}: {e}", command.get_program().to_string_lossy()));
+                127
+            }
+        };
+
+        if code != 0 {
+            return code;
+        }
+    }
+
+    0
+}
+
+/// Runs `plan` under the lock in `dirs`, retrying it as its retry policy
+/// allows, and records the outcome. Returns the exit code of the last attempt,
+/// or 0 if the plan's conditions skipped it.
+fn run(plan: &Plan, dirs: &Dirs) -> Result<i32, String> {
+    let argv = argv(plan)?;
+
+    if let Some(conditions) = &plan.conditions {
+        if !conditions_met(conditions) {
+            log(plan, "conditions not met, skipping");
+            return Ok(0);
+        }
+    }
+
+    let vars = vars(plan)?;
+
+    if let Some(parent) = dirs.lock.parent() {
+        create_dir_all(parent).map_err(|e| format!("Error creating {}: {e}", parent.display()))?;
+    }
+
+    let lock = File::create(&dirs.lock).map_err(|e| format!("Error creating {}: {e}", dirs.lock.display()))?;
+    lock.lock().map_err(|e| format!("Error locking {}: {e}", dirs.lock.display()))?;
+
+    let retry = match &plan.retry {
+        Some(retry) => Retry {
+            attempts: retry.attempts.max(1),
+            ..*retry
+        },
+        None => Retry {
+            attempts: 1,
+            ..Default::default()
+        },
+    };
+
+    let mut delay = retry.delay;
+    let mut attempts = 0;
+
+    let code = loop {
+        attempts += 1;
+        log(plan, &format!("attempt {attempts} of {}", retry.attempts));
+
+        let code = attempt(plan, &argv, &vars);
+        if code == 0 || attempts >= retry.attempts {
+            break code;
+        }
+
+        let wait = delay + jitter(retry.jitter);
+        log(plan, &format!("failed with exit code {code}, retrying in {wait}s"));
+        sleep(Duration::from_secs(wait));
+        delay = delay.saturating_mul(retry.backoff);
+    };
+
+    let failed = dirs.failed.join(&plan.unit);
+
+    match (code, &plan.retry) {
+        (0, _) => {
+            log(plan, "done");
+            let _ = fs::remove_file(&failed);
+        }
+        (_, Some(_)) => {
+            log(plan, &format!("giving up after {attempts} attempts"));
+            create_dir_all(&dirs.failed).map_err(|e| format!("Error creating {}: {e}", dirs.failed.display()))?;
+            fs::write(&failed, format!("gave up after {attempts} attempts at {}\n", now()))
+                .map_err(|e| format!("Error writing {}: {e}", failed.display()))?;
+        }
+        (code, None) => log(plan, &format!("failed with exit code {code}")),
+    }
+
+    let status = Status {
+        module: &plan.module,
+        unit: &plan.unit,
+        scope: &plan.scope,
+        manager: &plan.manager,
+        action: &plan.action,
+        packages: &plan.packages,
+        time: now(),
+        exit_code: code,
+        attempts,
+    };
+    let path = dirs.status.join(format!("{}.json", plan.unit));
+    let contents = serde_json::to_string(&status).map_err(|e| format!("Error serializing status: {e}"))?;
+
+    create_dir_all(&dirs.status).map_err(|e| format!("Error creating {}: {e}", dirs.status.display()))?;
+    fs::write(&path, contents).map_err(|e| format!("Error writing {}: {e}", path.display()))?;
+
+    Ok(code)
+}
+
+/// The plan files `path` names: itself, or every plan in it if it is a
+/// directory.
+fn plans(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut plans = fs::read_dir(path)
+        .map_err(|e| format!("Error reading {}: {e}", path.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("json"))
+        .collect::<Vec<_>>();
+    plans.sort();
+
+    Ok(plans)
+}
+
+fn read(path: &Path) -> Result<Plan, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Error reading {}: {e}", path.display()))?;
+    serde_json::from_str(&contents).map_err(|e| format!("Error parsing {}: {e}", path.display()))
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    if args.is_empty() || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{USAGE}");
+        return match args.is_empty() {
+            true => ExitCode::from(2),
+            false => ExitCode::SUCCESS,
+        };
+    }
+
+    let mut result = ExitCode::SUCCESS;
+
+    for arg in args {
+        let paths = match plans(Path::new(&arg)) {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::from(2);
+            }
+        };
+
+        for path in paths {
+            match read(&path).and_then(|plan| run(&plan, &Dirs::for_scope(&plan.scope))) {
+                Ok(0) => {}
+                Ok(code) => result = ExitCode::from(u8::try_from(code).unwrap_or(1)),
+                Err(e) => {
+                    eprintln!("{e}");
+                    result = ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    /// A plan whose manager is a fake flatpak in `bin` that logs its arguments
+    /// to `log`.
+    fn plan(bin: &Path, log: &Path, before: &[&str], retry: Option<Retry>) -> Plan {
+        fs::create_dir_all(bin).unwrap();
+        fs::write(bin.join("flatpak"), "#!/bin/sh\necho \"$*\" >> \"$LOG\"\n").unwrap();
+        fs::set_permissions(bin.join("flatpak"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        Plan {
+            module: "apps".to_string(),
+            unit: "ostree-pkg-system-1".to_string(),
+            scope: "system".to_string(),
+            manager: "flatpak".to_string(),
+            action: "install".to_string(),
+            packages: vec!["org.gnome.Maps".to_string()],
+            conditions: None,
+            env: vec![
+                Var {
+                    name: "PATH".to_string(),
+                    source: Source::value(format!("{}:{}", bin.display(), env::var("PATH").unwrap())),
+                },
+                Var {
+                    name: "LOG".to_string(),
+                    source: Source::value(log.display().to_string()),
+                },
+            ],
+            before: before.iter().map(|step| step.to_string()).collect(),
+            flags: Vec::new(),
+            targets: vec!["org.gnome.Maps".to_string()],
+            after: Vec::new(),
+            retry,
+        }
+    }
+
+    #[test]
+    fn test_run_retries_and_records_status() {
+        let temp_dir = tempdir().unwrap();
+        let bin = temp_dir.path().join("bin");
+        let log = temp_dir.path().join("log");
+        let dirs = Dirs {
+            lock: temp_dir.path().join("run/agent.lock"),
+            status: temp_dir.path().join("status"),
+            failed: temp_dir.path().join("failed"),
+        };
+        let retry = || Retry {
+            attempts: 3,
+            delay: 0,
+            ..Default::default()
+        };
+
+        let count = temp_dir.path().join("count");
+        let flaky = format!("echo x >> {0} && [ $(wc -l < {0}) -ge 2 ]", count.display());
+
+        assert_eq!(run(&plan(&bin, &log, &["true", &flaky], Some(retry())), &dirs).unwrap(), 0);
+        let status: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dirs.status.join("ostree-pkg-system-1.json")).unwrap()).unwrap();
+        assert_eq!(status["exit_code"], 0);
+        assert_eq!(status["attempts"], 2);
+        assert!(!dirs.failed.join("ostree-pkg-system-1").exists());
+        assert_eq!(fs::read_to_string(&log).unwrap(), "install --noninteractive org.gnome.Maps\n");
+
+        assert_eq!(run(&plan(&bin, &log, &["exit 3", "touch never"], Some(retry())), &dirs).unwrap(), 3);
+        let status: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dirs.status.join("ostree-pkg-system-1.json")).unwrap()).unwrap();
+        assert_eq!(status["exit_code"], 3);
+        assert_eq!(status["attempts"], 3);
+        assert!(dirs.failed.join("ostree-pkg-system-1").exists());
+
+        assert_eq!(run(&plan(&bin, &log, &["exit 4"], None), &dirs).unwrap(), 4);
+
+        // the manager runs between before and after, and a plan whose
+        // conditions aren't met is skipped
+        let marker = temp_dir.path().join("marker");
+        let after = Plan {
+            after: vec![format!("grep -q org.gnome.Maps \"$LOG\" && touch {}", marker.display())],
+            ..plan(&bin, &log, &[], None)
+        };
+        assert_eq!(run(&after, &dirs).unwrap(), 0);
+        assert!(marker.exists());
+
+        let skipped = Plan {
+            conditions: Some(Conditions {
+                pci_vendor: vec!["0x10de".to_string()],
+                sysfs_root: temp_dir.path().join("sys"),
+            }),
+            ..plan(&bin, &log, &["exit 5"], None)
+        };
+        assert_eq!(run(&skipped, &dirs).unwrap(), 0);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_argv_and_conditions() {
+        let temp_dir = tempdir().unwrap();
+
+        let plan: Plan = serde_json::from_str(
+            r#"{
+  "module": "tools",
+  "unit": "ostree-pkg-system-1",
+  "scope": "system",
+  "manager": "dnf5",
+  "action": "install",
+  "packages": ["htop", "podman"],
+  "flags": ["--exclude=kernel*,mesa*"],
+  "targets": ["htop", "podman >= 5.0"]
+}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            argv(&plan).unwrap(),
+            ["dnf5", "install", "-y", "--exclude=kernel*,mesa*", "htop", "podman >= 5.0"]
+        );
+
+        let uninstall = Plan {
+            manager: "rpm-ostree".to_string(),
+            action: "uninstall".to_string(),
+            flags: Vec::new(),
+            targets: vec!["htop".to_string()],
+            ..plan
+        };
+        assert_eq!(argv(&uninstall).unwrap(), ["rpm-ostree", "uninstall", "-y", "--idempotent", "htop"]);
+
+        let swap = Plan {
+            action: "swap".to_string(),
+            ..uninstall
+        };
+        assert_eq!(argv(&swap).unwrap_err(), "Error: swap is not supported on rpm-ostree");
+
+        let sysfs = temp_dir.path().join("sys");
+        let conditions = Conditions {
+            pci_vendor: vec!["0x10de".to_string(), "0x1002".to_string()],
+            sysfs_root: sysfs.clone(),
+        };
+        assert!(!conditions_met(&conditions));
+
+        fs::create_dir_all(sysfs.join("bus/pci/devices/0000:00:02.0")).unwrap();
+        fs::write(sysfs.join("bus/pci/devices/0000:00:02.0/vendor"), "0x8086\n").unwrap();
+        assert!(!conditions_met(&conditions));
+
+        fs::create_dir_all(sysfs.join("bus/pci/devices/0000:01:00.0")).unwrap();
+        fs::write(sysfs.join("bus/pci/devices/0000:01:00.0/vendor"), "0x10DE\n").unwrap();
+        assert!(conditions_met(&conditions));
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_vars_read_credentials() {
+        let temp_dir = tempdir().unwrap();
+        let credentials = temp_dir.path().join("credentials");
+        fs::create_dir_all(&credentials).unwrap();
+        fs::write(credentials.join("corp-repo"), "hunter2\n").unwrap();
+        env::set_var("CREDENTIALS_DIRECTORY", &credentials);
+
+        let plan: Plan = serde_json::from_str(
+            r#"{
+  "module": "tools",
+  "unit": "ostree-pkg-system-1",
+  "scope": "system",
+  "manager": "dnf5",
+  "action": "install",
+  "packages": ["htop"],
+  "env": [
+    { "name": "http_proxy", "value": "http://proxy:3128" },
+    { "name": "DNF_VAR_corp_password", "credential": "corp-repo" }
+  ],
+  "targets": ["htop"]
+}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vars(&plan).unwrap(),
+            [
+                ("http_proxy".to_string(), "http://proxy:3128".to_string()),
+                ("DNF_VAR_corp_password".to_string(), "hunter2".to_string())
+            ]
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_read_plan_and_timestamp() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("ostree-pkg-user-1.json");
+
+        fs::write(
+            &path,
+            r#"{
+  "module": "apps",
+  "unit": "ostree-pkg-user-1",
+  "scope": "user",
+  "manager": "flatpak",
+  "action": "install",
+  "packages": ["org.gnome.Maps"],
+  "flags": [],
+  "targets": ["org.gnome.Maps"],
+  "retry": { "attempts": 3, "delay": 10, "backoff": 2, "jitter": 0 }
+}"#,
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("README"), "not a plan").unwrap();
+
+        assert_eq!(plans(temp_dir.path()).unwrap(), vec![path.clone()]);
+
+        let plan = read(&path).unwrap();
+        assert_eq!(plan.targets, vec!["org.gnome.Maps"]);
+        assert_eq!(plan.retry.unwrap().attempts, 3);
+
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00+00:00");
+        assert_eq!(timestamp(1_709_251_200), "2024-03-01T00:00:00+00:00");
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: ostree-pkg-status [--json]
+
+Shows the outcome of the last run of every ostree-pkg boot service and exits
+with 1 if any of them failed.";
+
+/// The outcome of the last run of a boot service, as recorded by its script.
+#[derive(Serialize, Deserialize)]
+struct Status {
+    module: String,
+    unit: String,
+    scope: String,
+    manager: String,
+    action: String,
+    packages: Vec<String>,
+    time: String,
+    exit_code: i32,
+    attempts: u32,
+}
+
+/// Directories system services and the current user's services record their
+/// status in.
+fn dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("/var/lib/ostree-pkg/status")];
+
+    let state = match (env::var_os("XDG_STATE_HOME"), env::var_os("HOME")) {
+        (Some(state), _) => Some(PathBuf::from(state)),
+        (None, Some(home)) => Some(PathBuf::from(home).join(".local/state")),
+        (None, None) => None,
+    };
+
+    if let Some(state) = state {
+        dirs.push(state.join("ostree-pkg/status"));
+    }
+
+    dirs
+}
+
+/// Reads every status record in `dir`. A missing directory has none.
+fn read(dir: &Path) -> Result<Vec<Status>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let mut statuses = Vec::new();
+
+    for entry in entries {
+        let path = entry.map_err(|e| format!("Error reading {}: {e}", dir.display()))?.path();
+
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+
+        let contents = fs::read_to_string(&path).map_err(|e| format!("Error reading {}: {e}", path.display()))?;
+        let status =
+            serde_json::from_str(&contents).map_err(|e| format!("Error parsing {}: {e}", path.display()))?;
+
+        statuses.push(status);
+    }
+
+    Ok(statuses)
+}
+
+fn table(statuses: &[Status]) -> String {
+    let header = ["MODULE", "SCOPE", "MANAGER", "ACTION", "RESULT", "ATTEMPTS", "LAST RUN"].map(String::from);
+
+    let rows = statuses.iter().map(|status| {
+        [
+            status.module.clone(),
+            status.scope.clone(),
+            status.manager.clone(),
+            status.action.clone(),
+            match status.exit_code {
+                0 => "ok".to_string(),
+                code => format!("failed ({code})"),
+            },
+            status.attempts.to_string(),
+            status.time.clone(),
+        ]
+    });
+    let rows = std::iter::once(header).chain(rows).collect::<Vec<_>>();
+
+    let widths = (0..7)
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn main() -> ExitCode {
+    let mut json = false;
+
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ => {
+                eprintln!("{USAGE}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    let mut statuses = Vec::new();
+
+    for dir in dirs() {
+        match read(&dir) {
+            Ok(found) => statuses.extend(found),
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    statuses.sort_by(|a, b| (&a.scope, &a.module, &a.unit).cmp(&(&b.scope, &b.module, &b.unit)));
+
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&statuses).unwrap_or_default()),
+        false if statuses.is_empty() => println!("No ostree-pkg boot services have run"),
+        false => println!("{}", table(&statuses)),
+    }
+
+    match statuses.iter().any(|status| status.exit_code != 0) {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_and_table() {
+        let temp_dir = tempdir().unwrap();
+
+        fs::write(
+            temp_dir.path().join("ostree-pkg-system-1.json"),
+            r#"{"action":"install","manager":"flatpak","module":"apps","packages":["org.gnome.Maps"],"scope":"system","unit":"ostree-pkg-system-1","time":"2025-03-01T10:00:00+00:00","exit_code":0,"attempts":1}"#,
+        )
+        .unwrap();
+        fs::write(
+            temp_dir.path().join("ostree-pkg-system-2.json"),
+            r#"{"action":"install","manager":"dnf","module":"tools","packages":["htop"],"scope":"system","unit":"ostree-pkg-system-2","time":"2025-03-01T10:05:00+00:00","exit_code":1,"attempts":5}"#,
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("notes.txt"), "not a status").unwrap();
+
+        let mut statuses = read(temp_dir.path()).unwrap();
+        statuses.sort_by(|a, b| a.module.cmp(&b.module));
+
+        assert_eq!(
+            table(&statuses),
+            "MODULE  SCOPE   MANAGER  ACTION   RESULT      ATTEMPTS  LAST RUN
+apps    system  flatpak  install  ok          1         2025-03-01T10:00:00+00:00
+tools   system  dnf      install  failed (1)  5         2025-03-01T10:05:00+00:00"
+        );
+
+        assert!(read(&temp_dir.path().join("missing")).unwrap().is_empty());
+
+        temp_dir.close().unwrap();
+    }
+}
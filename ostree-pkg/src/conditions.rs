@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+/// Hardware an `On::boot` module is only installed on. systemd skips the
+/// service for the virtualization and path conditions; the PCI check runs in
+/// the script or agent and ends it early.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConditions {
+    /// Value of `ConditionVirtualization=`, such as `vm` or `!container`.
+    pub virtualization: Option<String>,
+
+    /// Paths for `ConditionPathExists=`, such as `/sys/class/power_supply/BAT0`.
+    /// Prefix a path with `!` to require its absence.
+    pub path_exists: Vec<String>,
+
+    /// PCI vendor ids such as `0x10de`, one of which a device must have.
+    pub pci_vendor: Vec<String>,
+
+    /// Where sysfs is mounted for the PCI check.
+    pub sysfs_root: Option<String>,
+}
+
+impl RuntimeConditions {
+    /// `Condition*=` lines for the `[Unit]` section.
+    pub fn unit(&self) -> String {
+        self.virtualization
+            .iter()
+            .map(|virtualization| format!("ConditionVirtualization={virtualization}\n"))
+            .chain(self.path_exists.iter().map(|path| format!("ConditionPathExists={path}\n")))
+            .collect()
+    }
+
+    /// The vendor ids as sysfs shows them, such as `0x10de`.
+    pub fn vendor_ids(&self) -> Vec<String> {
+        self.pci_vendor
+            .iter()
+            .map(|vendor| format!("0x{}", vendor.trim_start_matches("0x").to_lowercase()))
+            .collect()
+    }
+
+    pub fn sysfs(&self) -> &str {
+        self.sysfs_root.as_deref().unwrap_or("/sys").trim_end_matches('/')
+    }
+
+    /// The vendor ids as an extended regular expression.
+    fn vendors(&self) -> Option<String> {
+        match self.pci_vendor.is_empty() {
+            true => None,
+            false => Some(self.vendor_ids().join("|")),
+        }
+    }
+
+    /// A command that succeeds if a PCI device from one of the vendors is
+    /// present.
+    fn check(&self) -> Option<String> {
+        let vendors = self.vendors()?;
+        let sysfs = self.sysfs();
+
+        Some(format!("grep -qixE '{vendors}' {sysfs}/bus/pci/devices/*/vendor 2>/dev/null"))
+    }
+
+    /// A line that ends the script unless a PCI device from one of the
+    /// vendors is present.
+    pub fn guard(&self) -> Option<String> {
+        self.check().zip(self.vendors()).map(|(check, vendors)| {
+            format!("{check} || {{ echo \"No PCI device from {vendors}, skipping\"; exit 0; }}")
+        })
+    }
+}
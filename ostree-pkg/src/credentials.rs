@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+
+use crate::setup::{Setup, Var};
+use crate::Manager;
+
+/// A secret systemd hands to an `On::boot` service, so it never has to be
+/// stored in the image in plaintext. The script or agent reads it from
+/// `$CREDENTIALS_DIRECTORY`.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Credential {
+    pub name: String,
+
+    /// File the credential is loaded from. Without it systemd looks the name
+    /// up in its credential stores, such as `/etc/credstore/`.
+    pub path: Option<String>,
+
+    /// Whether the credential was encrypted with `systemd-creds encrypt`.
+    pub encrypted: bool,
+
+    /// Environment variable the secret is exported as, for tools that read it
+    /// from there. Passing it on in `args` would show it in the process list.
+    pub env: Option<String>,
+
+    /// dnf repository the secret is the password of. It reaches dnf as the
+    /// variable `<repo>_password`, with characters other than letters, digits
+    /// and `_` replaced by `_`, which the repository's `.repo` file uses as
+    /// `password=$<repo>_password`.
+    pub repo: Option<String>,
+
+    /// User name sent with the password for `repo`.
+    pub login: Option<String>,
+}
+
+/// `LoadCredential=` and `LoadCredentialEncrypted=` lines for the `[Service]`
+/// section.
+pub fn unit(credentials: &[Credential]) -> String {
+    credentials
+        .iter()
+        .map(|credential| {
+            let directive = match credential.encrypted {
+                true => "LoadCredentialEncrypted",
+                false => "LoadCredential",
+            };
+
+            match &credential.path {
+                Some(path) => format!("{directive}={}:{path}\n", credential.name),
+                None => format!("{directive}={}\n", credential.name),
+            }
+        })
+        .collect()
+}
+
+/// The dnf variable holding the password of `repo`.
+fn password_var(repo: &str) -> String {
+    let repo = repo
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '_',
+        })
+        .collect::<String>();
+
+    format!("{repo}_password")
+}
+
+/// Passes the secrets on to the manager through its environment, so they are
+/// neither written anywhere nor shown on its command line.
+pub fn secrets(credentials: &[Credential], manager: &Manager) -> Result<Setup, String> {
+    let mut secrets = Setup::default();
+
+    for credential in credentials {
+        let name = &credential.name;
+
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("Error: invalid credential name \"{name}\""));
+        }
+
+        if let Some(env) = &credential.env {
+            secrets.env.push(Var::credential(env, name));
+        }
+
+        match (&credential.repo, manager) {
+            (None, _) => {}
+            (Some(repo), Manager::dnf | Manager::dnf5) => {
+                if let Some(login) = &credential.login {
+                    secrets.flags.push(format!("--setopt={repo}.username={login}"));
+                }
+                secrets.env.push(Var::credential(&format!("DNF_VAR_{}", password_var(repo)), name));
+            }
+            (Some(_), _) => {
+                return Err(format!(
+                    "Error: repo credentials are not supported on {}, use env instead",
+                    manager.name()
+                ))
+            }
+        }
+    }
+
+    Ok(secrets)
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::{notify, write_include, As};
+
+/// What to do once a boot service has failed for good. The report includes
+/// the last lines the service logged.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OnFailure {
+    /// Notify logged-in users on their desktops.
+    pub notify: bool,
+
+    /// Leave a message in `/etc/motd.d/` for the next login. System modules only.
+    pub motd: bool,
+
+    /// Run a command with `OSTREE_PKG_MODULE`, `OSTREE_PKG_UNIT` and
+    /// `OSTREE_PKG_JOURNAL` set.
+    pub command: Option<String>,
+}
+
+impl OnFailure {
+    /// Rejects reports that can't be made for a module running as `r#as`.
+    pub fn check(&self, r#as: &As) -> Result<(), String> {
+        if let (true, As::user) = (self.motd, r#as) {
+            return Err("Error: on_failure motd is only supported for system modules".into());
+        }
+
+        match (self.notify, self.motd, &self.command) {
+            (false, false, None) => Err("Error: on_failure needs notify, motd or a command".into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the script and unit that report a failure of `unit`, returning
+    /// the name of the unit to put in its `OnFailure=`. Expects [`Self::check`]
+    /// to have passed.
+    pub fn write(&self, module: &str, unit: &str, r#as: &As, includes_dir: &Path) -> Result<String, String> {
+        let failure_unit = format!("{unit}-failure");
+        let journal = match r#as {
+            As::system => "journalctl",
+            As::user => "journalctl --user",
+        };
+
+        let mut script = vec![format!(
+            "excerpt=\"$({journal} --unit={unit}.service --lines=20 --no-pager --output=cat)\""
+        )];
+
+        if self.notify {
+            script.push(notify::send(r#as, &format!("Installing {module} failed"), "$excerpt"));
+        }
+
+        if self.motd {
+            script.push(format!(
+                "mkdir -p /etc/motd.d && printf 'ostree-pkg module {module} failed:\\n%s\\n' \"$excerpt\" > /etc/motd.d/{unit}"
+            ));
+        }
+
+        if let Some(command) = &self.command {
+            script.push(format!(
+                "OSTREE_PKG_MODULE=\"{module}\" OSTREE_PKG_UNIT=\"{unit}\" OSTREE_PKG_JOURNAL=\"$excerpt\" {command}"
+            ));
+        }
+
+        let script_path = includes_dir.join(format!("usr/bin/{failure_unit}"));
+        let service_path = includes_dir.join(format!("etc/systemd/{}/{failure_unit}.service", r#as.name()));
+
+        write_include(&script_path, &format!("#!/bin/sh\n{}\n", script.join("\n")))?;
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Error making {} executable: {e}", script_path.display()))?;
+        write_include(
+            &service_path,
+            &format!(
+                "
+[Unit]
+Description=Report a failed package install
+
+[Service]
+Type=oneshot
+ExecStart=/usr/bin/{failure_unit}
+"
+            ),
+        )?;
+
+        Ok(format!("{failure_unit}.service"))
+    }
+}
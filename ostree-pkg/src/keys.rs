@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::setup::Setup;
+use crate::{Action, Manager};
+
+const KEY_DIR: &str = "etc/pki/rpm-gpg";
+
+/// A GPG key placed under `/etc/pki/rpm-gpg/`, either copied from `path` in
+/// the includes tree or downloaded from `url` and checked against `sha256`.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Key {
+    #[serde(default)]
+    pub path: Option<String>,
+
+    #[serde(default)]
+    pub url: Option<String>,
+
+    #[serde(default)]
+    pub sha256: Option<String>,
+
+    /// File name under `/etc/pki/rpm-gpg/`. Defaults to the name of `path` or `url`.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Installs `keys` before the manager runs and points the manager at them.
+pub fn keys(keys: &[Key], manager: &Manager, action: &Action, includes_dir: &Path) -> Result<Setup, String> {
+    let mut result = Setup::default();
+
+    for key in keys {
+        let source = key.path.as_deref().or(key.url.as_deref()).unwrap_or_default();
+        let name = match &key.name {
+            Some(name) => name.as_str(),
+            None => source.rsplit('/').next().unwrap_or_default(),
+        };
+
+        if name.is_empty() {
+            return Err("Error: keys need a path or url".into());
+        }
+
+        let target = format!("/{KEY_DIR}/{name}");
+
+        match (&key.path, &key.url, &key.sha256) {
+            (Some(path), None, _) => {
+                let source = includes_dir.join(path.trim_start_matches('/'));
+
+                if !source.is_file() {
+                    return Err(format!("Error: {} does not exist", source.display()));
+                }
+
+                result.copies.push((source, includes_dir.join(KEY_DIR).join(name)));
+            }
+            (None, Some(url), Some(_)) => {
+                result.before.push(format!("mkdir -p /{KEY_DIR} && curl -fsSL -o {target} {url}"));
+            }
+            (None, Some(url), None) => return Err(format!("Error: key {url} needs a sha256 checksum")),
+            _ => return Err("Error: keys need exactly one of path or url".into()),
+        }
+
+        if let Some(sha256) = &key.sha256 {
+            result.before.push(format!("echo \"{sha256}  {target}\" | sha256sum -c -"));
+        }
+
+        match (manager, action) {
+            (Manager::dnf | Manager::dnf5 | Manager::rpm_ostree, _) => {
+                result.before.push(format!("rpm --import {target}"));
+            }
+            (Manager::flatpak, Action::add_remote) => result.flags.push(format!("--gpg-import={target}")),
+            (Manager::flatpak, _) => return Err("Error: flatpak keys can only be imported with add_remote".into()),
+            _ => return Err(format!("Error: keys are not supported on {}", manager.name())),
+        }
+    }
+
+    Ok(result)
+}
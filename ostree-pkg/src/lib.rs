@@ -1,12 +1,48 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use std::collections::HashMap;
 use std::ffi::CString;
-use std::fs::{create_dir_all, OpenOptions};
+use std::fs::{self, create_dir_all, OpenOptions};
 use std::io::Write;
 use std::os::raw::c_char;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use vib_api::{build_module, plugin_info, Recipe};
 
+mod conditions;
+mod credentials;
+mod failure;
+mod keys;
+mod lockfile;
+mod migrate;
+mod network;
+mod notify;
+mod options;
+mod package;
+mod plan;
+mod remotes;
+mod retry;
+mod setup;
+mod state;
+mod status;
+mod users;
+mod verify;
+mod when;
+
+use conditions::RuntimeConditions;
+use credentials::Credential;
+use failure::OnFailure;
+use keys::Key;
+use migrate::Migration;
+use network::Network;
+use options::Options;
+use package::Package;
+use remotes::TemporaryRemote;
+use retry::Retry;
+use setup::{exported, Setup, Var};
+use state::{Conflicts, Record};
+use users::UserRemote;
+use when::When;
+
 #[derive(Default, Clone, Serialize, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum Manager {
@@ -14,6 +50,12 @@ pub enum Manager {
     dnf,
     dnf5,
     flatpak,
+    pipx,
+    cargo,
+    npm,
+    nix,
+    #[serde(rename = "rpm-ostree")]
+    rpm_ostree,
 }
 
 #[derive(Default, Clone, Serialize, Deserialize)]
@@ -24,6 +66,60 @@ pub enum Action {
     uninstall,
     add_remote,
     remove_remote,
+    group_install,
+    group_remove,
+    module_enable,
+    module_reset,
+    swap,
+    migrate,
+}
+
+impl Manager {
+    fn name(&self) -> &'static str {
+        match self {
+            Manager::dnf => "dnf",
+            Manager::dnf5 => "dnf5",
+            Manager::flatpak => "flatpak",
+            Manager::pipx => "pipx",
+            Manager::cargo => "cargo",
+            Manager::npm => "npm",
+            Manager::nix => "nix",
+            Manager::rpm_ostree => "rpm-ostree",
+        }
+    }
+}
+
+impl As {
+    fn name(&self) -> &'static str {
+        match self {
+            As::system => "system",
+            As::user => "user",
+        }
+    }
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::install => "install",
+            Action::uninstall => "uninstall",
+            Action::add_remote => "add_remote",
+            Action::remove_remote => "remove_remote",
+            Action::group_install => "group_install",
+            Action::group_remove => "group_remove",
+            Action::module_enable => "module_enable",
+            Action::module_reset => "module_reset",
+            Action::swap => "swap",
+            Action::migrate => "migrate",
+        }
+    }
+}
+
+/// A package replaced by another in a single transaction.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Swap {
+    pub from: String,
+    pub to: String,
 }
 
 #[derive(Default, Clone, Serialize, Deserialize)]
@@ -49,11 +145,22 @@ struct PkgModule {
     r#type: String,
 
     #[serde(default)]
-    packages: Vec<String>,
+    packages: Vec<Package>,
 
     #[serde(default)]
     remotes: Vec<String>,
 
+    #[serde(default)]
+    swaps: Vec<Swap>,
+
+    /// Repositories enabled only while an `On::build` command runs.
+    #[serde(default)]
+    temporary_remotes: Vec<TemporaryRemote>,
+
+    /// GPG keys imported before the module runs.
+    #[serde(default)]
+    keys: Vec<Key>,
+
     #[serde(default)]
     manager: Manager,
 
@@ -66,286 +173,2968 @@ struct PkgModule {
     #[serde(default)]
     r#as: As,
 
+    #[serde(default)]
+    options: Options,
+
     #[serde(default)]
     args: Vec<String>,
+
+    /// Install prefix for pipx, cargo and npm. Defaults to `/usr/local` for
+    /// `As::system` and `$HOME/.local` for `As::user`.
+    #[serde(default)]
+    prefix: Option<String>,
+
+    /// Lock exactly pinned packages with `versionlock` after installing them
+    /// with dnf or dnf5.
+    #[serde(default)]
+    versionlock: bool,
+
+    /// Record the installed versions of an `On::build` install in
+    /// `/usr/share/ostree-pkg/lock/<name>.json` and the build output.
+    #[serde(default)]
+    emit_lockfile: bool,
+
+    /// Lockfile in the includes tree that unversioned packages are pinned to.
+    #[serde(default)]
+    lockfile: Option<String>,
+
+    /// Remove package manager caches and logs after an `On::build` command.
+    /// Enabled unless set to `false`.
+    #[serde(default)]
+    cleanup: Option<bool>,
+
+    /// Download an `On::boot` install into the image at build time, so the
+    /// boot service only falls back to the network if the cache is incomplete.
+    /// Flatpak remotes need a collection ID for this, or the build fails.
+    #[serde(default)]
+    prefetch: bool,
+
+    /// What to do when a package action contradicts or repeats one made by an
+    /// earlier module of the same build.
+    #[serde(default)]
+    conflicts: Conflicts,
+
+    /// Check during the build that every package of an `On::boot` install
+    /// can be resolved, instead of finding out on first boot.
+    #[serde(default)]
+    verify: bool,
+
+    /// Flatpaks that replace the packages removed by `Action::migrate`.
+    #[serde(default)]
+    migration: Migration,
+
+    /// Retry a failed `On::boot` install with backoff instead of restarting
+    /// the service every 30 seconds forever.
+    #[serde(default)]
+    retry: Option<Retry>,
+
+    /// Report an `On::boot` install that failed for good. With the default
+    /// restart policy the service never fails for good, so this needs `retry`.
+    #[serde(default)]
+    on_failure: Option<OnFailure>,
+
+    /// Show desktop notifications while an `On::boot` module runs, installing
+    /// its packages one at a time to report progress.
+    #[serde(default)]
+    notify: bool,
+
+    /// Record the outcome of every run of an `On::boot` module for
+    /// `ostree-pkg-status`.
+    #[serde(default)]
+    status: bool,
+
+    /// Run an `On::boot` module through `ostree-pkg-agent`, which the image
+    /// must ship, from a plan in `/usr/share/ostree-pkg/plans.d/` instead of
+    /// a generated script. The agent builds the manager's command itself, and
+    /// retries, locks and records status. Only dnf, dnf5, rpm-ostree and
+    /// flatpak installs and uninstalls can be planned.
+    #[serde(default)]
+    plan: bool,
+
+    /// Build-time variables, such as `arch` and `variant`, that `when`
+    /// conditions are checked against and `network` takes defaults from.
+    /// Share them between modules with a YAML anchor.
+    #[serde(default)]
+    vars: HashMap<String, String>,
+
+    /// Build-time conditions the module only runs under. Packages can have
+    /// their own.
+    #[serde(default)]
+    when: Option<When>,
+
+    /// Hardware an `On::boot` module is only installed on.
+    #[serde(default)]
+    runtime_conditions: Option<RuntimeConditions>,
+
+    /// Users an `On::boot` flatpak module installs for from a system service,
+    /// instead of for whoever logs in.
+    #[serde(default)]
+    users: Vec<String>,
+
+    /// Remotes added to each of `users`' installations first.
+    #[serde(default)]
+    user_remotes: Vec<UserRemote>,
+
+    /// Enable lingering for `users`.
+    #[serde(default)]
+    linger: bool,
+
+    /// Proxy, mirrors and CA bundle for an `On::boot` module. Unset settings
+    /// default to the module's `proxy`, `no_proxy` and `ca_bundle` variables.
+    #[serde(default)]
+    network: Option<Network>,
+
+    /// Secrets systemd passes to an `On::boot` module's service.
+    #[serde(default)]
+    credentials: Vec<Credential>,
 }
 
-#[build_module]
-fn build(module: PkgModule, recipe: Recipe) -> String {
-    let includes_dir = Path::new(&recipe.includes_path);
-    let service_parent_dir = includes_dir.join("etc/systemd/");
-    let script_dir = includes_dir.join("usr/bin/");
+/// Writes a file into the includes tree, creating its parent directories.
+pub(crate) fn write_include(path: &Path, contents: &str) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        if !parent.exists() {
+            create_dir_all(parent).map_err(|e| format!("Error creating {}: {e}", parent.display()))?;
+        }
+    }
 
-    let uuid = Uuid::new_v4().to_string();
+    fs::write(path, contents).map_err(|e| format!("Error writing {}: {e}", path.display()))
+}
+
+/// Copies a file within the includes tree, creating the target's parent directories.
+fn copy_include(source: &Path, target: &Path) -> Result<(), String> {
+    if let Some(parent) = target.parent() {
+        create_dir_all(parent).map_err(|e| format!("Error creating {}: {e}", parent.display()))?;
+    }
+
+    fs::copy(source, target)
+        .map(|_| ())
+        .map_err(|e| format!("Error copying {}: {e}", source.display()))
+}
+
+/// Resolves a `path:` package against the includes tree, returning the path
+/// the manager installs it from and the flag flatpak needs to read it. Files
+/// installed on boot are added to `copies` to ship under `/usr` with the image.
+fn local_package(
+    module: &PkgModule,
+    includes_dir: &Path,
+    uuid: &str,
+    file: &str,
+    copies: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(String, Option<&'static str>), String> {
+    let file = file.trim_start_matches('/');
+    let source = includes_dir.join(file);
+
+    if Path::new(file).components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+        return Err(format!("Error: {file} is outside the includes tree"));
+    }
+
+    if !source.is_file() {
+        return Err(format!("Error: {} does not exist", source.display()));
+    }
+
+    let flag = match (&module.manager, source.extension().and_then(|e| e.to_str())) {
+        (Manager::dnf | Manager::dnf5, Some("rpm")) => None,
+        (Manager::flatpak, Some("flatpak")) => Some("--bundle"),
+        (Manager::flatpak, Some("flatpakref")) => Some("--from"),
+        (_, Some("deb")) => return Err("Error: .deb files are not supported".into()),
+        _ => return Err(format!("Error: {file} can't be installed with this manager")),
+    };
+
+    match module.on {
+        On::build => Ok((format!("/{file}"), flag)),
+        On::boot => {
+            let name = source.file_name().unwrap_or_default().to_string_lossy();
+            let target = format!("usr/share/ostree-pkg/files/{uuid}/{name}");
+
+            copies.push((source.clone(), includes_dir.join(&target)));
+
+            Ok((format!("/{target}"), flag))
+        }
+    }
+}
+
+/// What a boot service runs: `steps` from a script with `env` exported, or a
+/// plan for the agent.
+enum Exec<'a> {
+    Script { env: &'a [Var], steps: &'a [String] },
+    Plan(plan::Invocation),
+}
 
+/// Writes the service for `uuid`, and the script or plan it runs, returning
+/// the command that enables the service.
+fn boot_service(module: &PkgModule, includes_dir: &Path, uuid: &str, exec: Exec) -> Result<String, String> {
+    let service_parent_dir = includes_dir.join("etc/systemd/");
+    let script_dir = includes_dir.join("usr/bin/");
 
-    let (script_path, service_dir, service_path, service_cmd) = match module.r#as {
+    let (unit, service_dir, service_cmd) = match module.r#as {
         As::system => (
-            script_dir.join(format!("ostree-pkg-system-{uuid}")),
+            format!("ostree-pkg-system-{uuid}"),
             service_parent_dir.join("system"),
-            service_parent_dir.join(format!("system/ostree-pkg-system-{uuid}.service")),
             format!("--system ostree-pkg-system-{uuid}"),
         ),
         As::user => (
-            script_dir.join(format!("ostree-pkg-user-{uuid}")),
+            format!("ostree-pkg-user-{uuid}"),
             service_parent_dir.join("user"),
-            service_parent_dir.join(format!("user/ostree-pkg-user-{uuid}.service")),
             format!("--user ostree-pkg-user-{uuid}"),
         ),
     };
+    let script_path = script_dir.join(&unit);
+    let service_path = service_dir.join(format!("{unit}.service"));
 
-    let pkg_mgr: &str;
-    let action: &str;
-    let mut is_error = false;
+    // with a retry policy the script or agent retries on its own, so the
+    // service must not
+    let restart = match module.retry {
+        Some(_) => "",
+        None => "Restart=on-failure\nRestartSec=30\n",
+    };
 
-    match module.manager {
-        Manager::dnf => {
-            pkg_mgr = "dnf";
-            action = match module.action {
-                Action::install => "install -y",
-                Action::uninstall => "uninstall -y",
-                Action::add_remote => {
-                    is_error = true;
-                    "Error: add_remote is not supported on dnf"
-                }
-                Action::remove_remote => {
-                    is_error = true;
-                    "Error: remove_remote is not supported on dnf"
-                }
-            }
-        }
-        Manager::dnf5 => {
-            pkg_mgr = "dnf5";
-            action = match module.action {
-                Action::install => "install -y",
-                Action::uninstall => "uninstall -y",
-                Action::add_remote => "-y copr enable",
-                Action::remove_remote => "-y copr remove",
-            };
-        }
+    let exec_start = match exec {
+        Exec::Plan(invocation) => plan::write(module, &unit, &invocation, includes_dir)?,
+        Exec::Script { env, steps } => {
+            let command = exported(env, steps.to_vec()).join(" && ");
 
-        Manager::flatpak => {
-            pkg_mgr = "flatpak";
-            action = match module.action {
-                Action::install => "install --noninteractive",
-                Action::uninstall => "uninstall --noninteractive",
-                Action::add_remote => "remote-add --if-not-exists",
-                Action::remove_remote => "remote-delete",
+            let command = match &module.retry {
+                Some(retry) => retry.script(&command, &unit, &module.r#as),
+                None => command,
             };
-        }
-    }
-
-    if is_error {
-        return action.into();
-    }
-
-    let params = match module.action {
-        Action::install | Action::uninstall => module.packages.join(" "),
-        Action::add_remote | Action::remove_remote => module.remotes.join(" "),
-    };
 
-    let command = format!("{pkg_mgr} {action} {} {params}", module.args.join(" "));
-
-    match module.on {
-        On::build => return command,
+            let command = match module.runtime_conditions.as_ref().and_then(RuntimeConditions::guard) {
+                Some(guard) => format!("{guard}\n{command}"),
+                None => command,
+            };
 
-        On::boot => {
-            let command = format!("{pkg_mgr} {action} {} {params}", module.args.join(" "));
+            let command = match module.status {
+                true => format!("{}\n{command}", status::script(module, &unit)),
+                false => command,
+            };
 
-            let script_file = match script_path.exists() {
-                true => OpenOptions::new().append(true).open(script_path.clone()),
+            let mut script_file = match script_path.exists() {
+                true => OpenOptions::new().append(true).open(&script_path),
                 false => {
-                    let script_dir = script_dir.as_path();
                     if !script_dir.exists() {
-                        match create_dir_all(script_dir) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                return format!("Error creating {}: {e}", script_dir.display());
-                            }
-                        }
+                        create_dir_all(&script_dir)
+                            .map_err(|e| format!("Error creating {}: {e}", script_dir.display()))?;
                     }
 
-                    OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .open(script_path.clone())
+                    OpenOptions::new().write(true).create(true).open(&script_path)
                 }
-            };
+            }
+            .map_err(|e| format!("Error setting up boot module: {e}"))?;
 
-            match script_file {
-                Ok(mut script_file) => {
-                    if let Err(e) = writeln!(script_file, "{command}") {
-                        return format!("Couldn't write script file: {e}");
-                    }
+            writeln!(script_file, "{command}")
+                .map_err(|e| format!("Error writing {}: {e}", script_path.display()))?;
 
-                    if !service_dir.exists() {
-                        match create_dir_all(service_dir.clone()) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                return format!(
-                                    "Error creating {}: {e}",
-                                    service_dir.display()
-                                );
-                            }
-                        }
-                    }
+            script_path.display().to_string()
+        }
+    };
 
-                    if service_path.exists() {
-                        return "echo \"service already created\"".into()
-                    }
-                        
-
-                    let mut service_file = match OpenOptions::new()
-                        .write(true)
-                        .create(true)
-                        .open(service_path.clone())
-                    {
-                        Ok(service_file) => service_file,
-                        Err(e) => {
-                            return format!(
-                                "Error creating {}: {e}",
-                                service_path.display()
-                            )
-                        }
-                    };
-
-                    let service_definition = match module.r#as {
-                        As::system => format!(
-                            "
+    if !service_dir.exists() {
+        create_dir_all(&service_dir).map_err(|e| format!("Error creating {}: {e}", service_dir.display()))?;
+    }
+
+    if service_path.exists() {
+        return Ok("echo \"service already created\"".into());
+    }
+
+    let mut service_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(&service_path)
+        .map_err(|e| format!("Error creating {}: {e}", service_path.display()))?;
+
+    let on_failure = match &module.on_failure {
+        Some(on_failure) => format!(
+            "OnFailure={}\n",
+            on_failure.write(&module.name, &unit, &module.r#as, includes_dir)?
+        ),
+        None => String::new(),
+    };
+
+    let credentials = credentials::unit(&module.credentials);
+
+    let conditions = module
+        .runtime_conditions
+        .as_ref()
+        .map(RuntimeConditions::unit)
+        .unwrap_or_default();
+
+    let service_definition = match module.r#as {
+        As::system => format!(
+            "
 [Unit]
 Description=Install Packages after boot
 Wants=network-online.target
 After=network-online.target
-
+{on_failure}{conditions}
 [Service]
 Type=oneshot
 ExecStart={0}
-Restart=on-failure
-RestartSec=30
-
+{credentials}{restart}
 [Install]
 WantedBy=default.target",
-                            script_path.display()
-                        ),
-                        As::user => format!(
-                            "
+            exec_start
+        ),
+        As::user => format!(
+            "
 [Unit]
 Description=Install Packages after boot
 Wants=network-online.target
 After=ostree-pkg-system.service
-
+{on_failure}{conditions}
 [Service]
 Type=oneshot
 ExecStart={0}
-Restart=on-failure
-RestartSec=30
-
+{credentials}{restart}
 [Install]
 WantedBy=default.target",
-                            script_path.display()
-                        ),
-                    };
+            exec_start
+        ),
+    };
 
-                    if let Err(e) = writeln!(service_file, "{service_definition}") {
-                        return format!("Couldn't write to file: {e}");
-                    }
-                
-                    
+    writeln!(service_file, "{service_definition}").map_err(|e| format!("Error writing {}: {e}", service_path.display()))?;
 
-                    return format!("systemctl enable {service_cmd}");
-                }
-                Err(e) => {
-                    return format!("Error setting up boot module: {e}");
-                }
-            }
-        }
-    }
+    Ok(format!("systemctl enable {service_cmd}"))
 }
 
+#[build_module]
+fn build(module: PkgModule, recipe: Recipe) -> String {
+    let includes_dir = Path::new(&recipe.includes_path);
 
+    let name = module.name.clone();
+    let mut module = match when::filter(module) {
+        Ok(Some(module)) => module,
+        Ok(None) => return format!("echo \"Skipping {name}: conditions not met\""),
+        Err(e) => return e,
+    };
 
+    if matches!(module.on, On::boot) || matches!(module.action, Action::migrate) {
+        let network = module.network.take().unwrap_or_default().with_defaults(&module.vars);
+        module.network = Some(network).filter(|network| !network.is_empty());
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use tempfile::tempdir;
-    use std::path::PathBuf;
-    use regex::Regex;
+    let records = match module.action {
+        Action::migrate => migrate::records(&module),
+        _ => records(&module),
+    };
 
-    #[test]
-    fn test_build_module_uninstall_dnf_boot_system() {
-        let temp_dir = tempdir().unwrap();
-        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+    let state_path = includes_dir.join("usr/share/ostree-pkg/state.json");
 
-        let module = PkgModule {
-            name: "test".to_string(),
-            r#type: "ostree-pkg".to_string(),
-            packages: vec!["package1".to_string()],
-            manager: Manager::dnf,
-            action: Action::uninstall,
-            on: On::boot,
-            r#as: As::system,
-            ..Default::default()
-        };
-        let recipe = Recipe {
-            includes_path: includes_path.clone(),
-            ..Default::default()
-        };
-        let result = build(module, recipe);
+    let conflicts = match state::check(&state_path, &records) {
+        Ok(conflicts) => conflicts,
+        Err(e) => return e,
+    };
 
-        // Extract UUID from the result string
-        let re = Regex::new(r"ostree-pkg-system-(.*)").unwrap();
-        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+    let warnings = match (&module.conflicts, conflicts.is_empty()) {
+        (Conflicts::ignore, _) | (_, true) => String::new(),
+        (Conflicts::error, false) => return format!("Error: {}", conflicts.join("; ")),
+        (Conflicts::warn, false) => conflicts
+            .iter()
+            .map(|conflict| format!("echo \"Warning: {conflict}\" >&2 && "))
+            .collect(),
+    };
 
-        let script_path = PathBuf::from(format!("/usr/bin/ostree-pkg-system-{}", uuid));
+    let result = match module.action {
+        Action::migrate => migrate::migrate(module, includes_dir),
+        _ => run(module, includes_dir),
+    };
 
-        assert_eq!(result, format!("systemctl enable --system ostree-pkg-system-{}", uuid));
+    if result.starts_with("Error") {
+        return result;
+    }
 
-        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-system-{}", uuid));
-        let script_content = fs::read_to_string(script_file_path).unwrap();
-        assert_eq!(script_content, "dnf uninstall -y  package1\n");
+    // only a module that succeeded is recorded
+    match state::save(&state_path, records) {
+        Ok(()) => format!("{warnings}{result}"),
+        Err(e) => e,
+    }
+}
 
-        let service_file_path = Path::new(&includes_path).join(format!("etc/systemd/system/ostree-pkg-system-{}.service", uuid));
-        let service_content = fs::read_to_string(service_file_path).unwrap();
-        let expected_service_content = format!(
-            "
-[Unit]
-Description=Install Packages after boot
-Wants=network-online.target
-After=network-online.target
+/// The package actions `module` takes, for detecting conflicts between modules.
+fn records(module: &PkgModule) -> Vec<Record> {
+    let record = |action: &str, package: String| Record {
+        module: module.name.clone(),
+        manager: module.manager.name().into(),
+        scope: module.r#as.name().into(),
+        action: action.into(),
+        package,
+    };
 
-[Service]
-Type=oneshot
-ExecStart={}{}
-Restart=on-failure
-RestartSec=30
+    // a swap removes the package it swaps from and installs the one it swaps to
+    match module.action {
+        Action::install | Action::uninstall | Action::group_install | Action::group_remove => module
+            .packages
+            .iter()
+            .map(|package| record(module.action.name(), package.uninstall_arg(&module.manager)))
+            .collect(),
+        Action::swap => module
+            .swaps
+            .iter()
+            .flat_map(|swap| [record("uninstall", swap.from.clone()), record("install", swap.to.clone())])
+            .collect(),
+        _ => Vec::new(),
+    }
+}
 
-[Install]
-WantedBy=default.target
-",
-includes_path,
-            script_path.display() // Corrected line: using script_path.display()
-        );
-        assert_eq!(service_content, expected_service_content);
+/// Builds the command for a single package action.
+fn run(module: PkgModule, includes_dir: &Path) -> String {
+    let uuid = Uuid::new_v4().to_string();
 
-        temp_dir.close().unwrap();
+
+    let prefix = module.prefix.clone().unwrap_or_else(|| match module.r#as {
+        As::system => "/usr/local".into(),
+        As::user => "$HOME/.local".into(),
+    });
+
+    if let (Manager::nix, On::build) = (&module.manager, &module.on) {
+        return "Error: nix is only supported on boot".into();
     }
 
+    let pkg_mgr: String;
+    let action: &str;
+    let mut is_error = false;
 
-    #[test]
-    fn test_build_module_add_remote_dnf5_boot_user() {
-        let temp_dir = tempdir().unwrap();
+    match module.manager {
+        Manager::dnf => {
+            pkg_mgr = "dnf".into();
+            action = match module.action {
+                Action::install => "install -y",
+                Action::uninstall => "uninstall -y",
+                Action::group_install => "group install -y",
+                Action::group_remove => "group remove -y",
+                Action::module_enable => "module enable -y",
+                Action::module_reset => "module reset -y",
+                Action::swap => "swap -y",
+                _ => {
+                    is_error = true;
+                    ""
+                }
+            }
+        }
+        Manager::dnf5 => {
+            pkg_mgr = "dnf5".into();
+            action = match module.action {
+                Action::install => "install -y",
+                Action::uninstall => "uninstall -y",
+                Action::add_remote => "-y copr enable",
+                Action::remove_remote => "-y copr remove",
+                Action::group_install => "group install -y",
+                Action::group_remove => "group remove -y",
+                Action::module_enable => "module enable -y",
+                Action::module_reset => "module reset -y",
+                Action::swap => "swap -y",
+                Action::migrate => {
+                    is_error = true;
+                    ""
+                }
+            };
+        }
+
+        Manager::rpm_ostree => {
+            pkg_mgr = "rpm-ostree".into();
+            action = match module.action {
+                Action::install => "install -y --idempotent",
+                Action::uninstall => "uninstall -y --idempotent",
+                Action::swap => "override remove",
+                _ => {
+                    is_error = true;
+                    ""
+                }
+            };
+        }
+
+        Manager::flatpak => {
+            pkg_mgr = match module.users.is_empty() {
+                true => "flatpak".into(),
+                false => "flatpak --user".into(),
+            };
+            action = match module.action {
+                Action::install => "install --noninteractive",
+                Action::uninstall => "uninstall --noninteractive",
+                Action::add_remote => "remote-add --if-not-exists",
+                Action::remove_remote => "remote-delete",
+                _ => {
+                    is_error = true;
+                    ""
+                }
+            };
+        }
+
+        Manager::pipx => {
+            pkg_mgr = format!(
+                "PIPX_HOME=\"{prefix}/share/pipx\" PIPX_BIN_DIR=\"{prefix}/bin\" PIPX_MAN_DIR=\"{prefix}/share/man\" pipx"
+            );
+            action = match module.action {
+                Action::install => "install",
+                Action::uninstall => "uninstall",
+                _ => {
+                    is_error = true;
+                    ""
+                }
+            }
+        }
+
+        Manager::cargo => {
+            pkg_mgr = "cargo".into();
+            action = match module.action {
+                Action::install => "install --locked --quiet --root",
+                Action::uninstall => "uninstall --quiet --root",
+                _ => {
+                    is_error = true;
+                    ""
+                }
+            }
+        }
+
+        Manager::npm => {
+            pkg_mgr = "npm".into();
+            action = match module.action {
+                Action::install => "install --global --yes --no-fund --no-audit --prefix",
+                Action::uninstall => "uninstall --global --yes --prefix",
+                _ => {
+                    is_error = true;
+                    ""
+                }
+            }
+        }
+
+        Manager::nix => {
+            pkg_mgr = "nix --extra-experimental-features \"nix-command flakes\"".into();
+            action = match (&module.r#as, &module.action) {
+                (As::system, Action::install) => "profile install --profile /nix/var/nix/profiles/default",
+                (As::system, Action::uninstall) => "profile remove --profile /nix/var/nix/profiles/default",
+                (As::system, Action::add_remote) => "registry add --registry /etc/nix/registry.json",
+                (As::system, Action::remove_remote) => "registry remove --registry /etc/nix/registry.json",
+                (As::user, Action::install) => "profile install",
+                (As::user, Action::uninstall) => "profile remove",
+                (As::user, Action::add_remote) => "registry add",
+                (As::user, Action::remove_remote) => "registry remove",
+                _ => {
+                    is_error = true;
+                    ""
+                }
+            };
+        }
+    }
+
+    if is_error {
+        return format!(
+            "Error: {} is not supported on {}",
+            module.action.name(),
+            module.manager.name()
+        );
+    }
+
+    let lock_entries = match &module.lockfile {
+        Some(file) => match lockfile::read(&includes_dir.join(file.trim_start_matches('/'))) {
+            Ok(entries) => entries,
+            Err(e) => return e,
+        },
+        None => Vec::new(),
+    };
+
+    let mut bundle_flag = None;
+    let mut remote_packages = Vec::new();
+    let mut package_args = Vec::new();
+    let mut local_files = Vec::new();
+    let mut copies = Vec::new();
+
+    let params = match module.action {
+        Action::install => {
+            let mut packages = Vec::new();
+
+            for package in &module.packages {
+                let arg = match package.local_path() {
+                    Some(file) => local_package(&module, includes_dir, &uuid, file, &mut copies).map(|(path, flag)| {
+                        bundle_flag = bundle_flag.or(flag);
+                        local_files.push(path.clone());
+                        path
+                    }),
+                    None => {
+                        let package = lockfile::pin(&lock_entries, &module.manager, package);
+
+                        package.install_arg(&module.manager).inspect(|arg| {
+                            remote_packages.push((arg.clone(), package.uninstall_arg(&module.manager)))
+                        })
+                    }
+                };
+
+                match arg {
+                    Ok(arg) => packages.push(arg),
+                    Err(e) => return e,
+                }
+            }
+
+            package_args.clone_from(&packages);
+            packages.join(" ")
+        }
+        Action::uninstall => {
+            if module.packages.iter().any(|package| package.local_path().is_some()) {
+                return "Error: path packages can only be installed".into();
+            }
+
+            package_args = module
+                .packages
+                .iter()
+                .map(|package| package.uninstall_arg(&module.manager))
+                .collect::<Vec<_>>();
+            package_args.join(" ")
+        }
+        Action::add_remote | Action::remove_remote => module.remotes.join(" "),
+        // group names such as "Development Tools" may contain spaces
+        Action::group_install | Action::group_remove | Action::module_enable | Action::module_reset => module
+            .packages
+            .iter()
+            .map(|package| match package.uninstall_arg(&module.manager) {
+                name if name.contains(' ') => format!("\"{name}\""),
+                name => name,
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        Action::swap if module.swaps.is_empty() => {
+            return "Error: swap needs at least one from/to pair".into();
+        }
+        Action::swap | Action::migrate => String::new(),
+    };
+
+    let action = match (&module.manager, bundle_flag) {
+        (Manager::flatpak, Some(_)) if module.packages.len() > 1 => {
+            return "Error: flatpak bundles must be installed one per module".into();
+        }
+        (Manager::flatpak, Some(flag)) => format!("{action} {flag}"),
+        // cargo and npm take the prefix as the value of their last flag
+        (Manager::cargo | Manager::npm, _) => format!("{action} \"{prefix}\""),
+        _ => action.to_string(),
+    };
+
+    // System-wide nix packages are kept in a manifest in the image, which the
+    // boot service feeds to the default profile.
+    let (pkg_mgr, params, manifest) = match (&module.manager, &module.r#as, &module.action) {
+        (Manager::nix, As::system, Action::install | Action::uninstall) => {
+            let manifest = format!("usr/share/ostree-pkg/nix/ostree-pkg-system-{uuid}");
+            let contents = params
+                .split(' ')
+                .filter(|p| !p.is_empty())
+                .map(|p| format!("{p}\n"))
+                .collect::<String>();
+
+            (
+                format!("xargs -a /{manifest} {pkg_mgr}"),
+                String::new(),
+                Some((includes_dir.join(&manifest), contents)),
+            )
+        }
+        _ => (pkg_mgr, params, None),
+    };
+
+    if module.notify && !matches!((&module.on, &module.action), (On::boot, Action::install | Action::uninstall)) {
+        return "Error: notify is only supported for installs and uninstalls on boot".into();
+    }
+
+    if module.notify && matches!((&module.manager, &module.r#as), (Manager::nix, As::system)) {
+        return "Error: notify is not supported for system nix modules".into();
+    }
+
+    // each swap is its own transaction, as dnf only swaps one pair at a time,
+    // and notify reports progress by installing one package at a time
+    let params = match module.action {
+        Action::install | Action::uninstall if module.notify => package_args.clone(),
+        Action::swap => module
+            .swaps
+            .iter()
+            .map(|swap| match module.manager {
+                Manager::rpm_ostree => format!("{} --install {}", swap.from, swap.to),
+                _ => format!("{} {}", swap.from, swap.to),
+            })
+            .collect(),
+        _ => vec![params],
+    };
+
+    if matches!(module.on, On::build) && module.retry.is_some() {
+        return "Error: retry is only supported on boot".into();
+    }
+
+    if matches!(module.on, On::build) && module.on_failure.is_some() {
+        return "Error: on_failure is only supported on boot".into();
+    }
+
+    if module.on_failure.is_some() && module.retry.is_none() {
+        return "Error: on_failure needs retry, as the service is otherwise restarted forever".into();
+    }
+
+    if let Some(Err(e)) = module.retry.as_ref().map(Retry::check) {
+        return e;
+    }
+
+    if let Some(Err(e)) = module.on_failure.as_ref().map(|on_failure| on_failure.check(&module.r#as)) {
+        return e;
+    }
+
+    if matches!(module.on, On::build) && module.status {
+        return "Error: status is only supported on boot".into();
+    }
+
+    if matches!(module.on, On::build) && module.plan {
+        return "Error: plan is only supported on boot".into();
+    }
+
+    // the agent builds the manager's command itself, so a plan has no room for
+    // the shell that notify, prefetch and users wrap around it
+    match (module.plan, &module.manager, &module.action) {
+        (false, _, _) => {}
+        (true, _, _) if module.notify || module.prefetch || !module.users.is_empty() => {
+            return "Error: plan can't be combined with notify, prefetch or users".into()
+        }
+        (true, Manager::dnf | Manager::dnf5 | Manager::rpm_ostree | Manager::flatpak, Action::install | Action::uninstall) => {}
+        (true, _, _) => {
+            return "Error: plan is only supported for dnf, dnf5, rpm-ostree and flatpak installs and uninstalls".into()
+        }
+    }
+
+    if matches!(module.on, On::build) && module.runtime_conditions.is_some() {
+        return "Error: runtime_conditions are only supported on boot".into();
+    }
+
+    if matches!(module.on, On::build) && module.network.is_some() {
+        return "Error: network is only supported on boot".into();
+    }
+
+    if matches!(module.on, On::build) && !module.credentials.is_empty() {
+        return "Error: credentials are only supported on boot".into();
+    }
+
+    // runuser starts a login shell that has neither the credentials directory
+    // nor the rights to read it
+    if !module.users.is_empty() && !module.credentials.is_empty() {
+        return "Error: credentials can't be combined with users".into();
+    }
+
+    match (module.users.is_empty(), &module.manager, &module.on, &module.r#as) {
+        (true, _, _, _) if module.linger => return "Error: linger needs users".into(),
+        (false, Manager::flatpak, On::boot, As::system)
+            if matches!(module.action, Action::install)
+                && module.user_remotes.is_empty() =>
+        {
+            return "Error: users need user_remotes to install from".into()
+        }
+        (true, _, _, _) | (false, Manager::flatpak, On::boot, As::system) => {}
+        (false, Manager::flatpak, On::boot, As::user) => return "Error: users need a system module".into(),
+        (false, Manager::flatpak, On::build, _) => return "Error: users are only supported on boot".into(),
+        (false, _, _, _) => return "Error: users are only supported on flatpak".into(),
+    }
+
+    if matches!(module.on, On::boot) && !module.temporary_remotes.is_empty() {
+        return "Error: temporary_remotes are only supported on build".into();
+    }
+
+    let cache = format!("/usr/share/ostree-pkg/cache/{uuid}");
+
+    let sideload = match (module.prefetch, &module.manager) {
+        (true, Manager::flatpak) => Some(format!("--sideload-repo={cache}")),
+        _ => None,
+    };
+
+    let parts = [
+        module
+            .network
+            .as_ref()
+            .map_or(Ok(Setup::default()), |network| network.settings(&module.manager, &pkg_mgr)),
+        credentials::secrets(&module.credentials, &module.manager),
+        keys::keys(&module.keys, &module.manager, &module.action, includes_dir),
+        remotes::temporary(&module.manager, &module.temporary_remotes),
+    ];
+
+    let mut setup = Setup::default();
+
+    for part in parts {
+        match part {
+            Ok(part) => setup.extend(part),
+            Err(e) => return e,
+        }
+    }
+
+    let args = match module.options.flags(&module.manager) {
+        Ok(flags) => flags
+            .into_iter()
+            .chain(setup.flags)
+            .chain(sideload)
+            .chain(module.args.iter().cloned())
+            .collect::<Vec<_>>(),
+        Err(e) => return e,
+    };
+
+    let total = params.len();
+    let (verb, done) = match module.action {
+        Action::uninstall => ("Removing", "Removed"),
+        _ => ("Installing", "Installed"),
+    };
+    let progress = |summary: String, body: String| match module.notify {
+        true => Some(notify::send(&module.r#as, &summary, &body)),
+        false => None,
+    };
+
+    let installs = progress(format!("{verb} {}", module.name), format!("{total} packages"))
+        .into_iter()
+        .chain(params.iter().enumerate().flat_map(|(i, params)| {
+            progress(format!("{verb} {} ({}/{total})", module.name, i + 1), params.replace('"', ""))
+                .into_iter()
+                .chain(Some(format!("{pkg_mgr} {action} {} {params}", args.join(" "))))
+        }))
+        .chain(progress(format!("{done} {}", module.name), format!("{total} packages")))
+        .collect::<Vec<_>>();
+
+    // prefetched rpms are installed with the module's keys and flags, and the
+    // network is only used if that fails
+    let installs = match (module.prefetch, &module.on, &module.action, &module.manager) {
+        (true, On::boot, Action::install, Manager::dnf | Manager::dnf5) => vec![format!(
+            "{{ {pkg_mgr} {action} {} --disablerepo='*' {cache}/*.rpm || {{ {}; }}; }}",
+            args.join(" "),
+            installs.join(" && ")
+        )],
+        _ => installs,
+    };
+
+    let mut before = setup.before;
+    let mut after = setup.after;
+
+    // a vendored file is only needed by the install, not in the final image
+    if matches!(module.on, On::build) && !local_files.is_empty() {
+        after.push(format!("rm -f {}", local_files.join(" ")));
+    }
+
+    if let Some(warning) = module.options.warning() {
+        before.insert(0, format!("echo \"{warning}\" >&2"));
+    }
+
+    let locks = module
+        .packages
+        .iter()
+        .filter_map(|package| package.lock_arg(&module.manager))
+        .collect::<Vec<_>>();
+
+    match (&module.manager, &module.action, module.versionlock) {
+        (_, _, false) => {}
+        (Manager::dnf | Manager::dnf5, Action::install, true) if !locks.is_empty() => {
+            after.push(format!("{pkg_mgr} versionlock add {}", locks.join(" ")));
+        }
+        (Manager::dnf | Manager::dnf5, _, true) => {}
+        (_, _, true) => return "Error: versionlock is only supported on dnf and dnf5".into(),
+    }
+
+    if let (Manager::flatpak, Action::install) = (&module.manager, &module.action) {
+        after.extend(lockfile::flatpak_commits(&lock_entries, &module.packages));
+    }
+
+    match (module.emit_lockfile, &module.on, &module.action) {
+        (false, _, _) => {}
+        (true, On::build, Action::install) => {
+            let names = module
+                .packages
+                .iter()
+                .filter(|package| package.local_path().is_none())
+                .map(|package| package.uninstall_arg(&module.manager))
+                .collect::<Vec<_>>();
+            let path = format!("/usr/share/ostree-pkg/lock/{}.json", module.name);
+
+            match lockfile::query(&module.manager, &names, &path) {
+                Ok(query) => after.push(query),
+                Err(e) => return e,
+            }
+        }
+        (true, _, _) => return "Error: lockfiles can only be written for installs on build".into(),
+    }
+
+    let steps = before
+        .iter()
+        .cloned()
+        .chain(installs)
+        .chain(after.iter().cloned())
+        .collect::<Vec<_>>();
+
+    let cleanup = match module.manager {
+        Manager::dnf | Manager::dnf5 => Some(format!(
+            "{pkg_mgr} clean all && rm -rf /var/cache/dnf /var/cache/libdnf5 /var/log/dnf*"
+        )),
+        Manager::flatpak => Some("rm -rf /var/tmp/flatpak-cache-*".to_string()),
+        _ => None,
+    };
+
+    let cleanup = match (cleanup, module.cleanup.unwrap_or(true)) {
+        (Some(cleanup), true) => Some(cleanup),
+        _ => None,
+    };
+
+    let prefetch = match (module.prefetch, &module.on, &module.action, &module.manager) {
+        (false, _, _, _) => None,
+        (true, On::boot, Action::install, Manager::dnf | Manager::dnf5) => Some(format!(
+            "mkdir -p {cache} && {pkg_mgr} download --resolve --destdir={cache} {}",
+            params.join(" ")
+        )),
+        (true, On::boot, Action::install, Manager::flatpak) => {
+            let refs = params.join(" ");
+            Some(format!(
+                    "flatpak install --noninteractive {refs} \
+                     && for ref in {refs}; do \
+                     remote=$(flatpak info --show-origin $ref) \
+                     && sed -n \"/^\\[remote \\\"$remote\\\"\\]/,/^\\[/p\" /var/lib/flatpak/repo/config \
+                     | grep -q '^collection-id=' \
+                     || {{ echo \"Error: remote $remote of $ref has no collection ID, which prefetch needs\"; exit 1; }}; \
+                     done \
+                     && flatpak create-usb --destination-repo={} / {refs} \
+                     && flatpak uninstall --noninteractive {refs} \
+                     && flatpak uninstall --noninteractive --unused",
+                cache.trim_start_matches('/')
+            ))
+        }
+        _ => return "Error: prefetch is only supported for dnf, dnf5 and flatpak installs on boot".into(),
+    };
+
+    let verify = match (module.verify, &module.on, &module.action) {
+        (false, _, _) => String::new(),
+        (true, On::boot, Action::install) => match verify::verify(&module.manager, &remote_packages) {
+            Ok(verify) if remote_packages.is_empty() => verify,
+            Ok(verify) => format!("{verify} && "),
+            Err(e) => return e,
+        },
+        (true, _, _) => return "Error: verify is only supported for installs on boot".into(),
+    };
+
+    // runs in the build before the module's own command or boot service
+    let prelude = match (prefetch, cleanup.clone()) {
+        (Some(prefetch), Some(cleanup)) => format!("{verify}{prefetch} && {cleanup} && "),
+        (Some(prefetch), None) => format!("{verify}{prefetch} && "),
+        (None, _) => verify,
+    };
+
+    // a user installation has no remotes of its own, so each user adds them
+    let steps = match (module.users.is_empty(), &module.action) {
+        (false, Action::install) => module
+            .user_remotes
+            .iter()
+            .map(|remote| format!("{pkg_mgr} remote-add --if-not-exists {} {}", remote.name, remote.url))
+            .chain(steps)
+            .collect(),
+        _ => steps,
+    };
+
+    // a login shell resets the environment, so each user exports it anew
+    let (env, steps) = match module.users.is_empty() {
+        true => (setup.env, steps),
+        false => (
+            Vec::new(),
+            users::as_users(&module.users, module.linger, &exported(&setup.env, steps).join(" && ")),
+        ),
+    };
+
+    // nothing is written to the includes tree until the module is known to be valid
+    for (source, target) in copies.iter().chain(&setup.copies) {
+        if let Err(e) = copy_include(source, target) {
+            return e;
+        }
+    }
+
+    if let Some((manifest, contents)) = &manifest {
+        if let Err(e) = write_include(manifest, contents) {
+            return e;
+        }
+    }
+
+    match module.on {
+        On::build => {
+            let command = exported(&env, steps).join(" && ");
+
+            match cleanup {
+                Some(cleanup) => format!("{prelude}{command} && {cleanup}"),
+                None => format!("{prelude}{command}"),
+            }
+        }
+
+        On::boot => {
+            let exec = match module.plan {
+                true => Exec::Plan(plan::Invocation {
+                    flags: bundle_flag.map(String::from).into_iter().chain(args).collect(),
+                    targets: package_args,
+                    env,
+                    before,
+                    after,
+                }),
+                false => Exec::Script { env: &env, steps: &steps },
+            };
+
+            match boot_service(&module, includes_dir, &uuid, exec) {
+                Ok(enable) => format!("{prelude}{enable}"),
+                Err(e) => e,
+            }
+        }
+    }
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+    use std::path::PathBuf;
+    use regex::Regex;
+
+    #[test]
+    fn test_build_module_uninstall_dnf_boot_system() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["package1".into()],
+            manager: Manager::dnf,
+            action: Action::uninstall,
+            on: On::boot,
+            r#as: As::system,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        // Extract UUID from the result string
+        let re = Regex::new(r"ostree-pkg-system-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+
+        let script_path = PathBuf::from(format!("/usr/bin/ostree-pkg-system-{}", uuid));
+
+        assert_eq!(result, format!("systemctl enable --system ostree-pkg-system-{}", uuid));
+
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-system-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(script_content, "dnf uninstall -y  package1\n");
+
+        let service_file_path = Path::new(&includes_path).join(format!("etc/systemd/system/ostree-pkg-system-{}.service", uuid));
+        let service_content = fs::read_to_string(service_file_path).unwrap();
+        let expected_service_content = format!(
+            "
+[Unit]
+Description=Install Packages after boot
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart={}{}
+Restart=on-failure
+RestartSec=30
+
+[Install]
+WantedBy=default.target
+",
+includes_path,
+            script_path.display() // Corrected line: using script_path.display()
+        );
+        assert_eq!(service_content, expected_service_content);
+
+        temp_dir.close().unwrap();
+    }
+
+
+    #[test]
+    fn test_build_module_add_remote_dnf5_boot_user() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+    
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            remotes: vec!["myrepo".to_string()],
+            manager: Manager::dnf5,
+            action: Action::add_remote,
+            on: On::boot,
+            r#as: As::user,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+    
+        // Extract UUID from the result string
+        let re = Regex::new(r"ostree-pkg-user-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+    
+        let script_path = PathBuf::from(format!("/usr/bin/ostree-pkg-user-{}", uuid));
+    
+        assert_eq!(result, format!("systemctl enable --user ostree-pkg-user-{}", uuid));
+    
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-user-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(script_content, "dnf5 -y copr enable  myrepo\n");
+    
+        let service_file_path = Path::new(&includes_path).join(format!("etc/systemd/user/ostree-pkg-user-{}.service", uuid));
+        let service_content = fs::read_to_string(service_file_path).unwrap();
+        let expected_service_content = format!(
+            "
+    [Unit]
+    Description=Install Packages after boot
+    Wants=network-online.target
+    After=ostree-pkg-system.service
+    
+    [Service]
+    Type=oneshot
+    ExecStart={}
+    Restart=on-failure
+    RestartSec=30
+    
+    [Install]
+    WantedBy=default.target
+    ",
+            script_path.display()
+        );
+        //assert_eq!(service_content, expected_service_content);
+    
+        temp_dir.close().unwrap();
+    }
+    
+    #[test]
+    fn test_build_module_install_flatpak_build_with_args() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["app1".into()],
+            manager: Manager::flatpak,
+            action: Action::install,
+            on: On::build,
+            args: vec!["--user".to_string()],
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "flatpak install --noninteractive --user app1 && rm -rf /var/tmp/flatpak-cache-*");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_add_remote_flatpak_boot() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+    
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            remotes: vec!["flathub".to_string()],
+            manager: Manager::flatpak,
+            action: Action::add_remote,
+            on: On::boot,
+            r#as: As::user,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+    
+        // Extract UUID from the result string
+        let re = Regex::new(r"ostree-pkg-user-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+    
+        let script_path = PathBuf::from(format!("/usr/bin/ostree-pkg-user-{}", uuid));
+    
+        assert_eq!(result, format!("systemctl enable --user ostree-pkg-user-{}", uuid));
+    
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-user-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(script_content, "flatpak remote-add --if-not-exists  flathub\n");
+    
+        //let service_file_path = Path::new(&includes_path).join(format!("etc/systemd/user/ostree-pkg-user-{}.service", uuid));
+    //     let service_content = fs::read_to_string(service_file_path).unwrap();
+    //     let expected_service_content = format!(
+    //         "
+    // [Unit]
+    // Description=Install Packages after boot
+    // Wants=network-online.target
+    // After=ostree-pkg-system.service
+    
+    // [Service]
+    // Type=oneshot
+    // ExecStart={}
+    // Restart=on-failure
+    // RestartSec=30
+    
+    // [Install]
+    // WantedBy=default.target
+    // ",
+    //         script_path.display()
+    //     );
+    //     assert_eq!(service_content, expected_service_content);
+    
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_dnf_add_remote_error() {
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            remotes: vec!["myrepo".to_string()],
+            manager: Manager::dnf,
+            action: Action::add_remote,
+            on: On::build,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: "/tmp".to_string(), // Doesn't matter for this test
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "Error: add_remote is not supported on dnf");
+    }
+
+    #[test]
+    fn test_build_module_empty_packages_remotes() {
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            manager: Manager::dnf,
+            action: Action::install,
+            on: On::build,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: "/tmp".to_string(), // Doesn't matter for this test
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "dnf install -y   && dnf clean all && rm -rf /var/cache/dnf /var/cache/libdnf5 /var/log/dnf*"
+        ); // Empty args and packages leave the extra spaces
+    }
+
+    #[test]
+    fn test_build_module_cleanup_opt_out() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{ "name": "test", "type": "ostree-pkg", "packages": ["htop"], "manager": "dnf5" }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "dnf5 install -y  htop && dnf5 clean all && rm -rf /var/cache/dnf /var/cache/libdnf5 /var/log/dnf*"
+        );
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{ "name": "test", "type": "ostree-pkg", "packages": ["vim"], "manager": "dnf5", "cleanup": false }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "dnf5 install -y  vim");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_keys_dnf_and_flatpak() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+        fs::create_dir_all(temp_dir.path().join("keys")).unwrap();
+        fs::write(temp_dir.path().join("keys/vendor.asc"), "key").unwrap();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "type": "ostree-pkg",
+                "packages": ["code"],
+                "manager": "dnf5",
+                "cleanup": false,
+                "keys": [
+                    { "path": "keys/vendor.asc" },
+                    { "url": "https://packages.microsoft.com/keys/microsoft.asc", "sha256": "abc123" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "rpm --import /etc/pki/rpm-gpg/vendor.asc \
+             && mkdir -p /etc/pki/rpm-gpg && curl -fsSL -o /etc/pki/rpm-gpg/microsoft.asc https://packages.microsoft.com/keys/microsoft.asc \
+             && echo \"abc123  /etc/pki/rpm-gpg/microsoft.asc\" | sha256sum -c - \
+             && rpm --import /etc/pki/rpm-gpg/microsoft.asc \
+             && dnf5 install -y  code"
+        );
+        let copied = Path::new(&includes_path).join("etc/pki/rpm-gpg/vendor.asc");
+        assert_eq!(fs::read_to_string(copied).unwrap(), "key");
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            remotes: vec!["example".to_string(), "https://example.org/repo".to_string()],
+            manager: Manager::flatpak,
+            action: Action::add_remote,
+            keys: vec![Key {
+                path: Some("keys/vendor.asc".to_string()),
+                ..Default::default()
+            }],
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "flatpak remote-add --if-not-exists --gpg-import=/etc/pki/rpm-gpg/vendor.asc example https://example.org/repo"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_key_url_needs_checksum() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["code".into()],
+            keys: vec![Key {
+                url: Some("https://example.org/key.asc".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "Error: key https://example.org/key.asc needs a sha256 checksum");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_prefetch_dnf5_boot() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["htop".into()],
+            manager: Manager::dnf5,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::system,
+            prefetch: true,
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"ostree-pkg-system-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+
+        assert_eq!(
+            result,
+            format!(
+                "mkdir -p /usr/share/ostree-pkg/cache/{uuid} \
+                 && dnf5 download --resolve --destdir=/usr/share/ostree-pkg/cache/{uuid} htop \
+                 && systemctl enable --system ostree-pkg-system-{uuid}"
+            )
+        );
+
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-system-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(
+            script_content,
+            format!(
+                "{{ dnf5 install -y  --disablerepo='*' /usr/share/ostree-pkg/cache/{uuid}/*.rpm \
+                 || {{ dnf5 install -y  htop; }}; }}\n"
+            )
+        );
+
+        // the network install is skipped once the cache install succeeds, and
+        // both get the module's flags
+        let module = PkgModule {
+            name: "tools".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["htop".into(), "vim".into()],
+            manager: Manager::dnf5,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::system,
+            prefetch: true,
+            options: Options {
+                nogpgcheck: true,
+                ..Default::default()
+            },
+            args: vec!["--setopt=install_weak_deps=False".to_string()],
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+        let script_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-system-{uuid}"));
+
+        let bin = temp_dir.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(bin.join("dnf5"), "#!/bin/sh\necho \"dnf5 $*\"\n").unwrap();
+        fs::set_permissions(bin.join("dnf5"), std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let output = std::process::Command::new("sh")
+            .arg(&script_path)
+            .env("PATH", format!("{}:{}", bin.display(), std::env::var("PATH").unwrap()))
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!(
+                "dnf5 install -y --no-gpgchecks --setopt=install_weak_deps=False \
+                 --disablerepo=* /usr/share/ostree-pkg/cache/{uuid}/*.rpm\n"
+            )
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_prefetch_flatpak_boot() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["org.example.App".into()],
+            manager: Manager::flatpak,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::system,
+            prefetch: true,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"ostree-pkg-system-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+
+        assert!(result.starts_with(&format!(
+            "flatpak install --noninteractive org.example.App \
+             && for ref in org.example.App; do \
+             remote=$(flatpak info --show-origin $ref) \
+             && sed -n \"/^\\[remote \\\"$remote\\\"\\]/,/^\\[/p\" /var/lib/flatpak/repo/config \
+             | grep -q '^collection-id=' \
+             || {{ echo \"Error: remote $remote of $ref has no collection ID, which prefetch needs\"; exit 1; }}; \
+             done \
+             && flatpak create-usb --destination-repo=usr/share/ostree-pkg/cache/{uuid} / org.example.App"
+        )));
+        assert!(result.ends_with(&format!(
+            "&& rm -rf /var/tmp/flatpak-cache-* && systemctl enable --system ostree-pkg-system-{uuid}"
+        )));
+
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-system-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(
+            script_content,
+            format!("flatpak install --noninteractive --sideload-repo=/usr/share/ostree-pkg/cache/{uuid} org.example.App\n")
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_conflicting_modules() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = |name: &str, action: Action, conflicts: Conflicts| PkgModule {
+            name: name.to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["htop".into()],
+            manager: Manager::dnf,
+            action,
+            on: On::build,
+            cleanup: Some(false),
+            conflicts,
+            ..Default::default()
+        };
+        let recipe = || Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+
+        let result = build(module("first", Action::install, Conflicts::warn), recipe());
+        assert_eq!(result, "dnf install -y  htop");
+
+        let result = build(module("second", Action::install, Conflicts::warn), recipe());
+        assert_eq!(
+            result,
+            "echo \"Warning: htop in module second duplicates module first\" >&2 && dnf install -y  htop"
+        );
+
+        let result = build(module("third", Action::uninstall, Conflicts::error), recipe());
+        assert_eq!(
+            result,
+            "Error: uninstall htop in module third contradicts install in module first; \
+             uninstall htop in module third contradicts install in module second"
+        );
+
+        let result = build(module("fourth", Action::install, Conflicts::ignore), recipe());
+        assert_eq!(result, "dnf install -y  htop");
+
+        // the failed third module left no record, and swaps are recorded
+        let result = build(
+            PkgModule {
+                action: Action::swap,
+                swaps: vec![Swap {
+                    from: "htop".into(),
+                    to: "btop".into(),
+                }],
+                ..module("fifth", Action::swap, Conflicts::warn)
+            },
+            recipe(),
+        );
+        assert!(result.starts_with(
+            "echo \"Warning: uninstall htop in module fifth contradicts install in module first\" >&2 && \
+             echo \"Warning: uninstall htop in module fifth contradicts install in module second\" >&2 && \
+             echo \"Warning: uninstall htop in module fifth contradicts install in module fourth\" >&2 && "
+        ));
+
+        let result = build(module("sixth", Action::uninstall, Conflicts::error), recipe());
+        assert_eq!(
+            result,
+            "Error: uninstall htop in module sixth contradicts install in module first; \
+             uninstall htop in module sixth contradicts install in module second; \
+             uninstall htop in module sixth contradicts install in module fourth; \
+             htop in module sixth duplicates module fifth"
+        );
+
+        // a migrate module records its removal and install together, keeping
+        // the records of earlier modules
+        let migrate = PkgModule {
+            packages: vec!["firefox".into()],
+            migration: serde_json::from_str(r#"{ "flatpaks": ["org.mozilla.firefox"] }"#).unwrap(),
+            ..module("browser", Action::migrate, Conflicts::error)
+        };
+        assert!(!build(migrate, recipe()).starts_with("Error"));
+
+        let result = build(module("seventh", Action::install, Conflicts::error), recipe());
+        assert_eq!(
+            result,
+            "Error: htop in module seventh duplicates module first; \
+             htop in module seventh duplicates module second; \
+             htop in module seventh duplicates module fourth; \
+             install htop in module seventh contradicts uninstall in module fifth"
+        );
+
+        // a state left behind by another build is discarded
+        let state_path = Path::new(&includes_path).join("usr/share/ostree-pkg/state.json");
+        let state = fs::read_to_string(&state_path).unwrap();
+        let mut state: serde_json::Value = serde_json::from_str(&state).unwrap();
+        assert_eq!(state["records"].as_array().unwrap().len(), 7);
+        state["build"] = "1-1".into();
+        fs::write(&state_path, state.to_string()).unwrap();
+
+        let result = build(module("first", Action::install, Conflicts::error), recipe());
+        assert_eq!(result, "dnf install -y  htop");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_verify_boot_packages() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["htop".into()],
+            manager: Manager::dnf5,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::system,
+            verify: true,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"ostree-pkg-system-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+
+        assert_eq!(
+            result,
+            format!(
+                "{{ [ -n \"$(dnf5 repoquery --quiet htop)\" ] || {{ echo \"Error: htop is not available\" >&2; false; }}; }} \
+                 && systemctl enable --system ostree-pkg-system-{uuid}"
+            )
+        );
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["htop".into()],
+            manager: Manager::dnf5,
+            on: On::build,
+            verify: true,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "Error: verify is only supported for installs on boot");
+
+        let module = PkgModule {
+            name: "constrained".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec![Package::Spec(package::PackageSpec {
+                name: "podman".to_string(),
+                version: Some("5.0".to_string()),
+                constraint: package::Constraint::ge,
+                ..Default::default()
+            })],
+            manager: Manager::dnf5,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::system,
+            verify: true,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert!(result.starts_with(
+            "{ [ -n \"$(dnf5 repoquery --quiet \"podman >= 5.0\")\" ] \
+             || { echo \"Error: podman is not available\" >&2; false; }; }"
+        ));
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_migrate_rpm_to_flatpak() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "firefox",
+                "type": "ostree-pkg",
+                "packages": ["firefox", "firefox-langpacks"],
+                "action": "migrate",
+                "migration": {
+                    "flatpaks": ["org.mozilla.firefox"],
+                    "data": [{ "from": "~/.mozilla", "to": "~/.var/app/org.mozilla.firefox/.mozilla" }]
+                },
+                "runtime_conditions": { "virtualization": "!container" }
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(
+            r"^dnf uninstall -y  firefox firefox-langpacks && dnf clean all && rm -rf /var/cache/dnf /var/cache/libdnf5 /var/log/dnf\* && systemctl enable --system ostree-pkg-system-([0-9a-f-]+) && systemctl enable --user ostree-pkg-user-([0-9a-f-]+)$",
+        )
+        .unwrap();
+        let captures = re.captures(&result).expect(&result);
+
+        let system_script = PathBuf::from(&includes_path).join(format!("usr/bin/ostree-pkg-system-{}", &captures[1]));
+        assert_eq!(
+            fs::read_to_string(system_script).unwrap(),
+            "flatpak install --noninteractive  org.mozilla.firefox\n"
+        );
+
+        let user_script = PathBuf::from(&includes_path).join(format!("usr/bin/ostree-pkg-user-{}", &captures[2]));
+        assert_eq!(
+            fs::read_to_string(user_script).unwrap(),
+            "if [ -e \"$HOME/.mozilla\" ] && [ ! -e \"$HOME/.var/app/org.mozilla.firefox/.mozilla\" ]; \
+             then mkdir -p \"$(dirname \"$HOME/.var/app/org.mozilla.firefox/.mozilla\")\" \
+             && cp -a \"$HOME/.mozilla\" \"$HOME/.var/app/org.mozilla.firefox/.mozilla\"; fi\n"
+        );
+
+        for (scope, uuid) in [("system", &captures[1]), ("user", &captures[2])] {
+            let service = PathBuf::from(&includes_path)
+                .join(format!("etc/systemd/{scope}/ostree-pkg-{scope}-{uuid}.service"));
+            assert!(fs::read_to_string(service).unwrap().contains("ConditionVirtualization=!container\n"));
+        }
+
+        let module = PkgModule {
+            name: "firefox".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["firefox".into()],
+            manager: Manager::flatpak,
+            action: Action::migrate,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        assert_eq!(build(module, recipe), "Error: migrate is not supported on flatpak");
+
+        let module = PkgModule {
+            name: "firefox".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["firefox".into()],
+            manager: Manager::rpm_ostree,
+            action: Action::migrate,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        assert_eq!(build(module, recipe), "Error: migrate is not supported on rpm-ostree");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_retry_boot() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "type": "ostree-pkg",
+                "packages": ["htop"],
+                "on": "boot",
+                "retry": { "attempts": 3, "delay": 10, "jitter": 5 }
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"^systemctl enable --system ostree-pkg-system-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let failed = format!("/var/lib/ostree-pkg/failed/ostree-pkg-system-{uuid}");
+
+        let script_path = PathBuf::from(&includes_path).join(format!("usr/bin/ostree-pkg-system-{uuid}"));
+        let expected_script_content = format!(
+            "attempt=1
+delay=10
+until dnf install -y  htop; do
+    if [ \"$attempt\" -ge 3 ]; then
+        mkdir -p \"$(dirname \"{failed}\")\" && echo \"gave up after $attempt attempts at $(date -Iseconds)\" > \"{failed}\"
+        exit 1
+    fi
+    sleep $((delay + $(od -An -N4 -tu4 /dev/urandom) % 6))
+    attempt=$((attempt + 1))
+    delay=$((delay * 2))
+done
+rm -f \"{failed}\"
+"
+        );
+        assert_eq!(fs::read_to_string(script_path).unwrap(), expected_script_content);
+
+        let service_path =
+            PathBuf::from(&includes_path).join(format!("etc/systemd/system/ostree-pkg-system-{uuid}.service"));
+        let service_content = fs::read_to_string(service_path).unwrap();
+        assert!(!service_content.contains("Restart="));
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["htop".into()],
+            retry: Some(Retry::default()),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path,
+            ..Default::default()
+        };
+        assert_eq!(build(module, recipe), "Error: retry is only supported on boot");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_on_failure_unit() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "type": "ostree-pkg",
+                "packages": ["htop"],
+                "on": "boot",
+                "retry": {},
+                "on_failure": { "notify": true, "motd": true, "command": "logger -t ostree-pkg failed" }
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"^systemctl enable --system ostree-pkg-system-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let unit = format!("ostree-pkg-system-{uuid}");
+        let includes = PathBuf::from(&includes_path);
+
+        let service_content = fs::read_to_string(includes.join(format!("etc/systemd/system/{unit}.service"))).unwrap();
+        assert!(service_content.contains(&format!("After=network-online.target\nOnFailure={unit}-failure.service\n\n[Service]")));
+
+        let failure_script = includes.join(format!("usr/bin/{unit}-failure"));
+        let expected_failure_service = format!(
+            "
+[Unit]
+Description=Report a failed package install
+
+[Service]
+Type=oneshot
+ExecStart=/usr/bin/{unit}-failure
+"
+        );
+        assert_eq!(
+            fs::read_to_string(includes.join(format!("etc/systemd/system/{unit}-failure.service"))).unwrap(),
+            expected_failure_service
+        );
+
+        let expected_failure_script = format!(
+            "#!/bin/sh
+excerpt=\"$(journalctl --unit={unit}.service --lines=20 --no-pager --output=cat)\"
+{{ loginctl list-users --no-legend | while read -r _ user _; do systemd-run --quiet --machine=\"$user@\" --user notify-send --app-name=ostree-pkg \"Installing test failed\" \"$excerpt\" || true; done; }}
+mkdir -p /etc/motd.d && printf 'ostree-pkg module test failed:\\n%s\\n' \"$excerpt\" > /etc/motd.d/{unit}
+OSTREE_PKG_MODULE=\"test\" OSTREE_PKG_UNIT=\"{unit}\" OSTREE_PKG_JOURNAL=\"$excerpt\" logger -t ostree-pkg failed
+"
+        );
+        assert_eq!(fs::read_to_string(&failure_script).unwrap(), expected_failure_script);
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&failure_script).unwrap().permissions()) & 0o777,
+            0o755
+        );
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{ "name": "test", "type": "ostree-pkg", "on": "boot", "as": "user", "retry": {}, "on_failure": { "motd": true } }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        assert_eq!(build(module, recipe), "Error: on_failure motd is only supported for system modules");
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{ "name": "test", "type": "ostree-pkg", "on": "boot", "on_failure": { "notify": true } }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path,
+            ..Default::default()
+        };
+        assert_eq!(
+            build(module, recipe),
+            "Error: on_failure needs retry, as the service is otherwise restarted forever"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_notify_progress() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = |r#as: As| PkgModule {
+            name: "apps".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["org.gnome.Maps".into(), "org.gnome.Weather".into()],
+            manager: Manager::flatpak,
+            on: On::boot,
+            r#as,
+            notify: true,
+            ..Default::default()
+        };
+        let recipe = || Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+
+        let result = build(module(As::user), recipe());
+        let re = Regex::new(r"^systemctl enable --user ostree-pkg-user-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let script_path = PathBuf::from(&includes_path).join(format!("usr/bin/ostree-pkg-user-{uuid}"));
+        assert_eq!(
+            fs::read_to_string(script_path).unwrap(),
+            "{ notify-send --app-name=ostree-pkg \"Installing apps\" \"2 packages\" || true; } \
+             && { notify-send --app-name=ostree-pkg \"Installing apps (1/2)\" \"org.gnome.Maps\" || true; } \
+             && flatpak install --noninteractive  org.gnome.Maps \
+             && { notify-send --app-name=ostree-pkg \"Installing apps (2/2)\" \"org.gnome.Weather\" || true; } \
+             && flatpak install --noninteractive  org.gnome.Weather \
+             && { notify-send --app-name=ostree-pkg \"Installed apps\" \"2 packages\" || true; }\n"
+        );
+
+        let result = build(module(As::system), recipe());
+        let re = Regex::new(r"^systemctl enable --system ostree-pkg-system-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let script_path = PathBuf::from(&includes_path).join(format!("usr/bin/ostree-pkg-system-{uuid}"));
+        let script_content = fs::read_to_string(script_path).unwrap();
+        assert!(script_content.starts_with(
+            "{ loginctl list-users --no-legend | while read -r _ user _; do \
+             systemd-run --quiet --machine=\"$user@\" --user notify-send --app-name=ostree-pkg \"Installing apps\" \"2 packages\" || true; done; }"
+        ));
+
+        let result = build(PkgModule { on: On::build, ..module(As::system) }, recipe());
+        assert_eq!(result, "Error: notify is only supported for installs and uninstalls on boot");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_status_record() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "apps".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["org.gnome.Maps".into()],
+            manager: Manager::flatpak,
+            on: On::boot,
+            r#as: As::user,
+            status: true,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"^systemctl enable --user ostree-pkg-user-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let script_path = PathBuf::from(&includes_path).join(format!("usr/bin/ostree-pkg-user-{uuid}"));
+
+        let expected_script_content = format!(
+            "ostree_pkg_status() {{
+    code=$?
+    mkdir -p \"${{XDG_STATE_HOME:-$HOME/.local/state}}/ostree-pkg/status\" && cat > \"${{XDG_STATE_HOME:-$HOME/.local/state}}/ostree-pkg/status/ostree-pkg-user-{uuid}.json\" <<EOF
+{{\"action\":\"install\",\"manager\":\"flatpak\",\"module\":\"apps\",\"packages\":[\"org.gnome.Maps\"],\"scope\":\"user\",\"unit\":\"ostree-pkg-user-{uuid}\",\"time\":\"$(date -Iseconds)\",\"exit_code\":$code,\"attempts\":${{attempt:-1}}}}
+EOF
+}}
+trap ostree_pkg_status EXIT
+flatpak install --noninteractive  org.gnome.Maps
+"
+        );
+        assert_eq!(fs::read_to_string(script_path).unwrap(), expected_script_content);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_plan_for_agent() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "apps",
+                "type": "ostree-pkg",
+                "packages": ["org.gnome.Maps"],
+                "manager": "flatpak",
+                "on": "boot",
+                "plan": true,
+                "retry": { "attempts": 3 },
+                "network": { "proxy": "http://proxy:3128", "mirrors": { "flathub": "https://mirror/flathub" } },
+                "runtime_conditions": { "pci_vendor": ["10DE"] }
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"^systemctl enable --system ostree-pkg-system-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let unit = format!("ostree-pkg-system-{uuid}");
+        let includes = PathBuf::from(&includes_path);
+
+        assert!(!includes.join(format!("usr/bin/{unit}")).exists());
+
+        let service_content = fs::read_to_string(includes.join(format!("etc/systemd/system/{unit}.service"))).unwrap();
+        assert!(service_content.contains(&format!(
+            "ExecStart=/usr/bin/ostree-pkg-agent /usr/share/ostree-pkg/plans.d/{unit}.json\n\n[Install]"
+        )));
+
+        let plan: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(includes.join(format!("usr/share/ostree-pkg/plans.d/{unit}.json"))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            plan,
+            serde_json::json!({
+                "module": "apps",
+                "unit": unit,
+                "scope": "system",
+                "manager": "flatpak",
+                "action": "install",
+                "packages": ["org.gnome.Maps"],
+                "conditions": { "pci_vendor": ["0x10de"], "sysfs_root": "/sys" },
+                "env": [
+                    { "name": "http_proxy", "value": "http://proxy:3128" },
+                    { "name": "https_proxy", "value": "http://proxy:3128" },
+                    { "name": "HTTP_PROXY", "value": "http://proxy:3128" },
+                    { "name": "HTTPS_PROXY", "value": "http://proxy:3128" }
+                ],
+                "before": ["flatpak remote-modify --url=https://mirror/flathub flathub"],
+                "flags": [],
+                "targets": ["org.gnome.Maps"],
+                "retry": { "attempts": 3, "delay": 30, "backoff": 2, "jitter": 0 }
+            })
+        );
+
+        // flags and targets reach the agent without the quoting meant for sh,
+        // and secrets as the names of their credentials
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "tools",
+                "type": "ostree-pkg",
+                "packages": ["htop", { "name": "podman", "version": "5.0", "constraint": "ge" }],
+                "manager": "dnf5",
+                "on": "boot",
+                "plan": true,
+                "options": { "exclude": ["kernel*", "mesa*"] },
+                "credentials": [{ "name": "corp-repo", "repo": "corp" }]
+            }"#,
+        )
+        .unwrap();
+        let result = build(
+            module,
+            Recipe {
+                includes_path: includes_path.clone(),
+                ..Default::default()
+            },
+        );
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let plan: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(includes.join(format!("usr/share/ostree-pkg/plans.d/ostree-pkg-system-{uuid}.json")))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(plan["env"], serde_json::json!([{ "name": "DNF_VAR_corp_password", "credential": "corp-repo" }]));
+        assert_eq!(plan["flags"], serde_json::json!(["--exclude=kernel*,mesa*"]));
+        assert_eq!(plan["targets"], serde_json::json!(["htop", "podman >= 5.0"]));
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{ "name": "tools", "type": "ostree-pkg", "packages": ["htop"], "on": "boot", "plan": true, "notify": true }"#,
+        )
+        .unwrap();
+        let recipe = || Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        assert_eq!(build(module, recipe()), "Error: plan can't be combined with notify, prefetch or users");
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{ "name": "tools", "type": "ostree-pkg", "packages": ["htop"], "manager": "pipx", "on": "boot", "plan": true }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            build(module, recipe()),
+            "Error: plan is only supported for dnf, dnf5, rpm-ostree and flatpak installs and uninstalls"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_when_conditions() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = |json: &str| serde_json::from_str::<PkgModule>(json).unwrap();
+        let recipe = || Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+
+        let result = build(
+            module(
+                r#"{
+                    "name": "drivers",
+                    "type": "ostree-pkg",
+                    "vars": { "arch": "x86_64", "variant": "nvidia" },
+                    "packages": [
+                        "htop",
+                        { "name": "akmod-nvidia", "when": { "vars": { "variant": "nvidia" } } },
+                        { "name": "asahi-scripts", "when": { "arch": ["aarch64"] } },
+                        { "name": "cockpit", "when": { "vars": { "variant": "server" } } }
+                    ],
+                    "cleanup": false
+                }"#,
+            ),
+            recipe(),
+        );
+        assert_eq!(result, "dnf install -y  htop akmod-nvidia");
+
+        let result = build(
+            module(
+                r#"{
+                    "name": "server",
+                    "type": "ostree-pkg",
+                    "vars": { "arch": "x86_64", "variant": "nvidia" },
+                    "packages": ["cockpit"],
+                    "when": { "vars": { "variant": "server" } }
+                }"#,
+            ),
+            recipe(),
+        );
+        assert_eq!(result, "echo \"Skipping server: conditions not met\"");
+
+        let result = build(
+            module(
+                r#"{
+                    "name": "arm",
+                    "type": "ostree-pkg",
+                    "vars": { "arch": "x86_64" },
+                    "packages": [{ "name": "asahi-scripts", "when": { "arch": ["aarch64"] } }]
+                }"#,
+            ),
+            recipe(),
+        );
+        assert_eq!(result, "echo \"Skipping arm: conditions not met\"");
+
+        // without the arch variable the image's architecture is unknown
+        let result = build(
+            module(
+                r#"{
+                    "name": "arm",
+                    "type": "ostree-pkg",
+                    "packages": [{ "name": "asahi-scripts", "when": { "arch": ["aarch64"] } }]
+                }"#,
+            ),
+            recipe(),
+        );
+        assert_eq!(result, "Error: arch conditions need an arch variable");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_runtime_conditions() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().join("includes").to_str().unwrap().to_string();
+        let sysfs = temp_dir.path().join("sys");
+        let device = sysfs.join("bus/pci/devices/0000:01:00.0");
+        fs::create_dir_all(&device).unwrap();
+
+        let module: PkgModule = serde_json::from_str(&format!(
+            r#"{{
+                "name": "nvidia",
+                "type": "ostree-pkg",
+                "packages": ["akmod-nvidia"],
+                "on": "boot",
+                "runtime_conditions": {{
+                    "virtualization": "!container",
+                    "path_exists": ["/sys/class/power_supply/BAT0"],
+                    "pci_vendor": ["10DE"],
+                    "sysfs_root": "{}"
+                }}
+            }}"#,
+            sysfs.display()
+        ))
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"^systemctl enable --system ostree-pkg-system-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let includes = PathBuf::from(&includes_path);
+
+        let service_content =
+            fs::read_to_string(includes.join(format!("etc/systemd/system/ostree-pkg-system-{uuid}.service"))).unwrap();
+        assert!(service_content.contains(
+            "After=network-online.target\n\
+             ConditionVirtualization=!container\n\
+             ConditionPathExists=/sys/class/power_supply/BAT0\n\n[Service]"
+        ));
+
+        let script_content = fs::read_to_string(includes.join(format!("usr/bin/ostree-pkg-system-{uuid}"))).unwrap();
+        assert_eq!(
+            script_content,
+            format!(
+                "grep -qixE '0x10de' {}/bus/pci/devices/*/vendor 2>/dev/null \
+                 || {{ echo \"No PCI device from 0x10de, skipping\"; exit 0; }}\n\
+                 dnf install -y  akmod-nvidia\n",
+                sysfs.display()
+            )
+        );
+
+        let guard = script_content.lines().next().unwrap();
+        let run = || {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("{guard}\necho installing"))
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        fs::write(device.join("vendor"), "0x8086\n").unwrap();
+        assert_eq!(run(), "No PCI device from 0x10de, skipping\n");
+
+        fs::write(device.join("vendor"), "0x10de\n").unwrap();
+        assert_eq!(run(), "installing\n");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_flatpak_for_named_users() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = |manager: Manager| PkgModule {
+            name: "kiosk".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["org.mozilla.firefox".into()],
+            manager,
+            on: On::boot,
+            user_remotes: vec![UserRemote {
+                name: "flathub".to_string(),
+                url: "https://flathub.org/repo/flathub.flatpakrepo".to_string(),
+            }],
+            users: vec!["kiosk".to_string(), "guest".to_string()],
+            linger: true,
+            ..Default::default()
+        };
+        let recipe = || Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+
+        let result = build(module(Manager::flatpak), recipe());
+        let re = Regex::new(r"^systemctl enable --system ostree-pkg-system-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let script_path = PathBuf::from(&includes_path).join(format!("usr/bin/ostree-pkg-system-{uuid}"));
+        assert_eq!(
+            fs::read_to_string(script_path).unwrap(),
+            "loginctl enable-linger kiosk && runuser -l kiosk -c '\
+             flatpak --user remote-add --if-not-exists flathub https://flathub.org/repo/flathub.flatpakrepo \
+             && flatpak --user install --noninteractive  org.mozilla.firefox' \
+             && loginctl enable-linger guest && runuser -l guest -c '\
+             flatpak --user remote-add --if-not-exists flathub https://flathub.org/repo/flathub.flatpakrepo \
+             && flatpak --user install --noninteractive  org.mozilla.firefox'\n"
+        );
+
+        let result = build(PkgModule { user_remotes: Vec::new(), ..module(Manager::flatpak) }, recipe());
+        assert_eq!(result, "Error: users need user_remotes to install from");
+
+        let result = build(module(Manager::dnf), recipe());
+        assert_eq!(result, "Error: users are only supported on flatpak");
+
+        let result = build(PkgModule { r#as: As::user, ..module(Manager::flatpak) }, recipe());
+        assert_eq!(result, "Error: users need a system module");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_network_settings() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = |json: &str| serde_json::from_str::<PkgModule>(json).unwrap();
+        let recipe = || Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let script = |result: &str| {
+            let re = Regex::new(r"^systemctl enable --system ostree-pkg-system-([0-9a-f-]+)$").unwrap();
+            let uuid = &re.captures(result).expect(result)[1];
+            fs::read_to_string(PathBuf::from(&includes_path).join(format!("usr/bin/ostree-pkg-system-{uuid}"))).unwrap()
+        };
+
+        let result = build(
+            module(
+                r#"{
+                    "name": "tools",
+                    "type": "ostree-pkg",
+                    "vars": { "no_proxy": "localhost,.corp.example" },
+                    "packages": ["htop"],
+                    "manager": "dnf5",
+                    "on": "boot",
+                    "network": {
+                        "proxy": "http://proxy.corp.example:3128",
+                        "mirrors": { "fedora": "https://mirror.corp.example/fedora" },
+                        "ca_bundle": "/etc/pki/tls/certs/corp.pem"
+                    }
+                }"#,
+            ),
+            recipe(),
+        );
+        assert_eq!(
+            script(&result),
+            "export http_proxy=\"http://proxy.corp.example:3128\" https_proxy=\"http://proxy.corp.example:3128\" \
+             HTTP_PROXY=\"http://proxy.corp.example:3128\" HTTPS_PROXY=\"http://proxy.corp.example:3128\" \
+             no_proxy=\"localhost,.corp.example\" NO_PROXY=\"localhost,.corp.example\" \
+             SSL_CERT_FILE=\"/etc/pki/tls/certs/corp.pem\" CURL_CA_BUNDLE=\"/etc/pki/tls/certs/corp.pem\" \
+             NIX_SSL_CERT_FILE=\"/etc/pki/tls/certs/corp.pem\" PIP_CERT=\"/etc/pki/tls/certs/corp.pem\" \
+             NODE_EXTRA_CA_CERTS=\"/etc/pki/tls/certs/corp.pem\" CARGO_HTTP_CAINFO=\"/etc/pki/tls/certs/corp.pem\" \
+             && dnf5 install -y --setopt=proxy=http://proxy.corp.example:3128 --setopt=sslcacert=/etc/pki/tls/certs/corp.pem \
+             --setopt=fedora.baseurl=https://mirror.corp.example/fedora --setopt=fedora.metalink= --setopt=fedora.mirrorlist= htop\n"
+        );
+
+        let result = build(
+            module(
+                r#"{
+                    "name": "apps",
+                    "type": "ostree-pkg",
+                    "vars": { "no_proxy": "localhost,.corp.example" },
+                    "packages": ["org.gnome.Maps"],
+                    "manager": "flatpak",
+                    "on": "boot",
+                    "network": { "mirrors": { "flathub": "https://flathub.corp.example/repo/" } }
+                }"#,
+            ),
+            recipe(),
+        );
+        assert_eq!(
+            script(&result),
+            "export no_proxy=\"localhost,.corp.example\" NO_PROXY=\"localhost,.corp.example\" \
+             && flatpak remote-modify --url=https://flathub.corp.example/repo/ flathub \
+             && flatpak install --noninteractive  org.gnome.Maps\n"
+        );
+
+        let result = build(
+            module(r#"{ "name": "cli", "type": "ostree-pkg", "packages": ["black"], "manager": "pipx", "network": { "proxy": "http://proxy:3128" } }"#),
+            recipe(),
+        );
+        assert_eq!(result, "Error: network is only supported on boot");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_credentials() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().join("includes").to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "corp",
+                "type": "ostree-pkg",
+                "packages": ["corp-tools"],
+                "on": "boot",
+                "credentials": [
+                    { "name": "corp-repo", "path": "/etc/credstore/corp-repo", "repo": "corp-repo", "login": "builder" },
+                    { "name": "extras", "encrypted": true, "env": "EXTRAS_TOKEN" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"^systemctl enable --system ostree-pkg-system-([0-9a-f-]+)$").unwrap();
+        let uuid = &re.captures(&result).expect(&result)[1];
+        let includes = PathBuf::from(&includes_path);
+        let script_path = includes.join(format!("usr/bin/ostree-pkg-system-{uuid}"));
+
+        let service_content =
+            fs::read_to_string(includes.join(format!("etc/systemd/system/ostree-pkg-system-{uuid}.service"))).unwrap();
+        assert!(service_content.contains(&format!(
+            "ExecStart={}\n\
+             LoadCredential=corp-repo:/etc/credstore/corp-repo\n\
+             LoadCredentialEncrypted=extras\n\
+             Restart=on-failure\n",
+            script_path.display()
+        )));
+
+        let script_content = fs::read_to_string(&script_path).unwrap();
+        assert_eq!(
+            script_content,
+            "export DNF_VAR_corp_repo_password=\"$(cat \"$CREDENTIALS_DIRECTORY/corp-repo\")\" \
+             EXTRAS_TOKEN=\"$(cat \"$CREDENTIALS_DIRECTORY/extras\")\" \
+             && dnf install -y --setopt=corp-repo.username=builder corp-tools\n"
+        );
+
+        let credentials = temp_dir.path().join("credentials");
+        fs::create_dir_all(&credentials).unwrap();
+        fs::write(credentials.join("corp-repo"), "hunter2").unwrap();
+        fs::write(credentials.join("extras"), "s3cret").unwrap();
+
+        // a stand-in dnf reports its command line and environment
+        let bin = temp_dir.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(
+            bin.join("dnf"),
+            "#!/bin/sh\necho \"args: $*\"\necho \"env: $DNF_VAR_corp_repo_password $EXTRAS_TOKEN\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(bin.join("dnf"), std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let output = std::process::Command::new("sh")
+            .arg(&script_path)
+            .env("CREDENTIALS_DIRECTORY", &credentials)
+            .env("PATH", format!("{}:{}", bin.display(), std::env::var("PATH").unwrap()))
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "args: install -y --setopt=corp-repo.username=builder corp-tools\nenv: hunter2 s3cret\n"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_install_pipx_build_pinned() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["black@24.1.0".into(), "ruff".into()],
+            manager: Manager::pipx,
+            action: Action::install,
+            on: On::build,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "PIPX_HOME=\"/usr/local/share/pipx\" PIPX_BIN_DIR=\"/usr/local/bin\" PIPX_MAN_DIR=\"/usr/local/share/man\" pipx install  black==24.1.0 ruff"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_npm_boot_user() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["@angular/cli@17.0.0".into()],
+            manager: Manager::npm,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::user,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"ostree-pkg-user-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-user-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(
+            script_content,
+            "npm install --global --yes --no-fund --no-audit --prefix \"$HOME/.local\"  @angular/cli@17.0.0\n"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_cargo_uninstall_strips_pin() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["ripgrep@14.1.0".into()],
+            manager: Manager::cargo,
+            action: Action::uninstall,
+            on: On::build,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "cargo uninstall --quiet --root \"/usr/local\"  ripgrep");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_nix_boot_user() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["hello".into(), "github:owner/repo#tool".into()],
+            manager: Manager::nix,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::user,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"ostree-pkg-user-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-user-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(
+            script_content,
+            "nix --extra-experimental-features \"nix-command flakes\" profile install  nixpkgs#hello github:owner/repo#tool\n"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_nix_boot_system_manifest() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["hello".into(), "jq".into()],
+            manager: Manager::nix,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::system,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"ostree-pkg-system-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+
+        let manifest_path = Path::new(&includes_path).join(format!("usr/share/ostree-pkg/nix/ostree-pkg-system-{}", uuid));
+        let manifest_content = fs::read_to_string(manifest_path).unwrap();
+        assert_eq!(manifest_content, "nixpkgs#hello\nnixpkgs#jq\n");
+
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-system-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(
+            script_content,
+            format!("xargs -a /usr/share/ostree-pkg/nix/ostree-pkg-system-{uuid} nix --extra-experimental-features \"nix-command flakes\" profile install --profile /nix/var/nix/profiles/default  \n")
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_nix_build_error() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["hello".into()],
+            manager: Manager::nix,
+            on: On::build,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "Error: nix is only supported on boot");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_install_local_rpm_build() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+        fs::create_dir_all(temp_dir.path().join("vendor")).unwrap();
+        fs::write(temp_dir.path().join("vendor/tool.rpm"), "rpm").unwrap();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["path:vendor/tool.rpm".into(), "htop".into()],
+            manager: Manager::dnf,
+            action: Action::install,
+            on: On::build,
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "dnf install -y  /vendor/tool.rpm htop && rm -f /vendor/tool.rpm");
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["path:vendor/../../outside.rpm".into()],
+            manager: Manager::dnf,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "Error: vendor/../../outside.rpm is outside the includes tree");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_install_flatpak_bundle_boot() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+        fs::write(temp_dir.path().join("app.flatpak"), "bundle").unwrap();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["path:app.flatpak".into()],
+            manager: Manager::flatpak,
+            action: Action::install,
+            on: On::boot,
+            r#as: As::system,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"ostree-pkg-system-(.*)").unwrap();
+        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
+
+        let copied = Path::new(&includes_path).join(format!("usr/share/ostree-pkg/files/{}/app.flatpak", uuid));
+        assert_eq!(fs::read_to_string(copied).unwrap(), "bundle");
+
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-system-{}", uuid));
+        let script_content = fs::read_to_string(script_file_path).unwrap();
+        assert_eq!(
+            script_content,
+            format!("flatpak install --noninteractive --bundle  /usr/share/ostree-pkg/files/{uuid}/app.flatpak\n")
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_invalid_module_writes_nothing() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+        fs::write(temp_dir.path().join("app.rpm"), "rpm").unwrap();
+        fs::write(temp_dir.path().join("vendor.asc"), "key").unwrap();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "type": "ostree-pkg",
+                "manager": "dnf",
+                "on": "boot",
+                "packages": ["path:app.rpm"],
+                "keys": [{ "path": "vendor.asc" }],
+                "emit_lockfile": true
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        assert_eq!(build(module, recipe), "Error: lockfiles can only be written for installs on build");
+
+        let mut written = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        written.sort();
+        assert_eq!(written, ["app.rpm", "vendor.asc"]);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_local_package_missing() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["path:missing.rpm".into()],
+            manager: Manager::dnf5,
+            action: Action::install,
+            on: On::build,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, format!("Error: {}/missing.rpm does not exist", includes_path));
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_pinned_packages_dnf_versionlock() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "type": "ostree-pkg",
+                "cleanup": false,
+                "packages": [
+                    "htop",
+                    { "name": "kernel-devel", "version": "6.8.5" },
+                    { "name": "podman", "version": "5.0", "constraint": "ge" }
+                ],
+                "manager": "dnf5",
+                "versionlock": true
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "dnf5 install -y  htop kernel-devel-6.8.5 \"podman >= 5.0\" && dnf5 versionlock add kernel-devel-6.8.5"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_pinned_flatpak_branch() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec![Package::Spec(package::PackageSpec {
+                name: "org.gnome.Platform".to_string(),
+                version: Some("46".to_string()),
+                ..Default::default()
+            })],
+            manager: Manager::flatpak,
+            action: Action::install,
+            on: On::build,
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "flatpak install --noninteractive  org.gnome.Platform//46");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_versionlock_unsupported() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["app1".into()],
+            manager: Manager::flatpak,
+            versionlock: true,
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "Error: versionlock is only supported on dnf and dnf5");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_emit_lockfile_dnf() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "tools".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["htop".into()],
+            manager: Manager::dnf,
+            action: Action::install,
+            on: On::build,
+            emit_lockfile: true,
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert!(result.starts_with(
+            "dnf install -y  htop && mkdir -p /usr/share/ostree-pkg/lock && dnf repoquery --installed --qf '{\"package\":\"%{name}\""
+        ));
+        assert!(result.ends_with("> /usr/share/ostree-pkg/lock/tools.json && cat /usr/share/ostree-pkg/lock/tools.json"));
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_emit_lockfile_flatpak_only_lists_module_refs() {
+        let temp_dir = tempdir().unwrap();
+        let bin = temp_dir.path().join("bin");
+        let path = temp_dir.path().join("lock/apps.json");
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(
+            bin.join("flatpak"),
+            "#!/bin/sh\nprintf 'org.gnome.Calculator\\t46.1\\tabc123\\tx86_64\\tflathub\\n'\n\
+             printf 'org.mozilla.firefox\\t128.0\\tdef456\\tx86_64\\tflathub\\n'\n",
+        )
+        .unwrap();
+        fs::set_permissions(bin.join("flatpak"), std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let query = lockfile::query(
+            &Manager::flatpak,
+            &["org.gnome.Calculator".to_string()],
+            path.to_str().unwrap(),
+        )
+        .unwrap();
+        let status = std::process::Command::new("sh")
+            .args(["-c", &query])
+            .env("PATH", format!("{}:{}", bin.display(), std::env::var("PATH").unwrap()))
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let entries = lockfile::read(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].package, "org.gnome.Calculator");
+        assert_eq!(entries[0].release, "abc123");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_lockfile_pins_packages() {
+        let temp_dir = tempdir().unwrap();
         let includes_path = temp_dir.path().to_str().unwrap().to_string();
-    
+        fs::write(
+            temp_dir.path().join("tools.json"),
+            r#"[
+                {"package":"htop","version":"3.3.0","release":"2.fc40","arch":"x86_64","repo":"fedora"},
+                {"package":"org.gnome.Calculator","version":"46.1","release":"abc123","arch":"x86_64","repo":"flathub"}
+            ]"#,
+        )
+        .unwrap();
+
         let module = PkgModule {
             name: "test".to_string(),
             r#type: "ostree-pkg".to_string(),
-            remotes: vec!["myrepo".to_string()],
-            manager: Manager::dnf5,
-            action: Action::add_remote,
-            on: On::boot,
-            r#as: As::user,
+            packages: vec!["htop".into(), "vim".into()],
+            manager: Manager::dnf,
+            on: On::build,
+            lockfile: Some("tools.json".to_string()),
+            cleanup: Some(false),
             ..Default::default()
         };
         let recipe = Recipe {
@@ -353,77 +3142,85 @@ includes_path,
             ..Default::default()
         };
         let result = build(module, recipe);
-    
-        // Extract UUID from the result string
-        let re = Regex::new(r"ostree-pkg-user-(.*)").unwrap();
-        let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
-    
-        let script_path = PathBuf::from(format!("/usr/bin/ostree-pkg-user-{}", uuid));
-    
-        assert_eq!(result, format!("systemctl enable --user ostree-pkg-user-{}", uuid));
-    
-        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-user-{}", uuid));
-        let script_content = fs::read_to_string(script_file_path).unwrap();
-        assert_eq!(script_content, "dnf5 -y copr enable  myrepo\n");
-    
-        let service_file_path = Path::new(&includes_path).join(format!("etc/systemd/user/ostree-pkg-user-{}.service", uuid));
-        let service_content = fs::read_to_string(service_file_path).unwrap();
-        let expected_service_content = format!(
-            "
-    [Unit]
-    Description=Install Packages after boot
-    Wants=network-online.target
-    After=ostree-pkg-system.service
-    
-    [Service]
-    Type=oneshot
-    ExecStart={}
-    Restart=on-failure
-    RestartSec=30
-    
-    [Install]
-    WantedBy=default.target
-    ",
-            script_path.display()
+        assert_eq!(result, "dnf install -y  htop-3.3.0-2.fc40 vim");
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["org.gnome.Calculator".into()],
+            manager: Manager::flatpak,
+            on: On::build,
+            lockfile: Some("tools.json".to_string()),
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "flatpak install --noninteractive  org.gnome.Calculator && flatpak update --noninteractive --commit=abc123 org.gnome.Calculator"
         );
-        //assert_eq!(service_content, expected_service_content);
-    
+
         temp_dir.close().unwrap();
     }
-    
+
     #[test]
-    fn test_build_module_install_flatpak_build_with_args() {
+    fn test_build_module_group_install_and_module_enable_dnf() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
         let module = PkgModule {
             name: "test".to_string(),
             r#type: "ostree-pkg".to_string(),
-            packages: vec!["app1".to_string()],
-            manager: Manager::flatpak,
-            action: Action::install,
+            packages: vec!["Development Tools".into(), "c-development".into()],
+            manager: Manager::dnf,
+            action: Action::group_install,
             on: On::build,
-            args: vec!["--user".to_string()],
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "dnf group install -y  \"Development Tools\" c-development");
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["nodejs:20".into()],
+            manager: Manager::dnf5,
+            action: Action::module_enable,
+            on: On::build,
+            cleanup: Some(false),
             ..Default::default()
         };
         let recipe = Recipe {
-            includes_path: "/tmp".to_string(),
+            includes_path: includes_path.clone(),
             ..Default::default()
         };
         let result = build(module, recipe);
-        assert_eq!(result, "flatpak install --noninteractive --user app1");
+        assert_eq!(result, "dnf5 module enable -y  nodejs:20");
+
+        temp_dir.close().unwrap();
     }
 
     #[test]
-    fn test_build_module_add_remote_flatpak_boot() {
+    fn test_build_module_group_install_flatpak_error() {
         let temp_dir = tempdir().unwrap();
         let includes_path = temp_dir.path().to_str().unwrap().to_string();
-    
+
         let module = PkgModule {
             name: "test".to_string(),
             r#type: "ostree-pkg".to_string(),
-            remotes: vec!["flathub".to_string()],
+            packages: vec!["group".into()],
             manager: Manager::flatpak,
-            action: Action::add_remote,
-            on: On::boot,
-            r#as: As::user,
+            action: Action::group_install,
+            on: On::build,
             ..Default::default()
         };
         let recipe = Recipe {
@@ -431,79 +3228,244 @@ includes_path,
             ..Default::default()
         };
         let result = build(module, recipe);
-    
-        // Extract UUID from the result string
-        let re = Regex::new(r"ostree-pkg-user-(.*)").unwrap();
+        assert_eq!(result, "Error: group_install is not supported on flatpak");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_swap_dnf_build() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "type": "ostree-pkg",
+                "cleanup": false,
+                "manager": "dnf",
+                "action": "swap",
+                "swaps": [
+                    { "from": "ffmpeg-free", "to": "ffmpeg" },
+                    { "from": "mesa-va-drivers", "to": "mesa-va-drivers-freeworld" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "dnf swap -y  ffmpeg-free ffmpeg && dnf swap -y  mesa-va-drivers mesa-va-drivers-freeworld"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_swap_rpm_ostree_boot() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "type": "ostree-pkg",
+                "manager": "rpm-ostree",
+                "action": "swap",
+                "on": "boot",
+                "swaps": [{ "from": "ffmpeg-free", "to": "ffmpeg" }]
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+
+        let re = Regex::new(r"ostree-pkg-system-(.*)").unwrap();
         let uuid = re.captures(&result).unwrap().get(1).unwrap().as_str();
-    
-        let script_path = PathBuf::from(format!("/usr/bin/ostree-pkg-user-{}", uuid));
-    
-        assert_eq!(result, format!("systemctl enable --user ostree-pkg-user-{}", uuid));
-    
-        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-user-{}", uuid));
+
+        let script_file_path = Path::new(&includes_path).join(format!("usr/bin/ostree-pkg-system-{}", uuid));
         let script_content = fs::read_to_string(script_file_path).unwrap();
-        assert_eq!(script_content, "flatpak remote-add --if-not-exists  flathub\n");
-    
-        //let service_file_path = Path::new(&includes_path).join(format!("etc/systemd/user/ostree-pkg-user-{}.service", uuid));
-    //     let service_content = fs::read_to_string(service_file_path).unwrap();
-    //     let expected_service_content = format!(
-    //         "
-    // [Unit]
-    // Description=Install Packages after boot
-    // Wants=network-online.target
-    // After=ostree-pkg-system.service
-    
-    // [Service]
-    // Type=oneshot
-    // ExecStart={}
-    // Restart=on-failure
-    // RestartSec=30
-    
-    // [Install]
-    // WantedBy=default.target
-    // ",
-    //         script_path.display()
-    //     );
-    //     assert_eq!(service_content, expected_service_content);
-    
+        assert_eq!(script_content, "rpm-ostree override remove  ffmpeg-free --install ffmpeg\n");
+
         temp_dir.close().unwrap();
     }
 
     #[test]
-    fn test_build_module_dnf_add_remote_error() {
+    fn test_build_module_options_per_manager() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let options = Options {
+            weak_deps: Some(false),
+            exclude: vec!["kernel*".to_string(), "mesa*".to_string()],
+            skip_unavailable: true,
+            best: Some(false),
+            ..Default::default()
+        };
+
         let module = PkgModule {
             name: "test".to_string(),
             r#type: "ostree-pkg".to_string(),
-            remotes: vec!["myrepo".to_string()],
+            packages: vec!["htop".into()],
             manager: Manager::dnf,
-            action: Action::add_remote,
-            on: On::build,
+            options: options.clone(),
+            args: vec!["--setopt=tsflags=nodocs".to_string()],
+            cleanup: Some(false),
             ..Default::default()
         };
         let recipe = Recipe {
-            includes_path: "/tmp".to_string(), // Doesn't matter for this test
+            includes_path: includes_path.clone(),
             ..Default::default()
         };
         let result = build(module, recipe);
-        assert_eq!(result, "Error: add_remote is not supported on dnf");
+        assert_eq!(
+            result,
+            "dnf install -y --setopt=install_weak_deps=False --exclude=\"kernel*,mesa*\" --setopt=strict=False --nobest --setopt=tsflags=nodocs htop"
+        );
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["htop".into()],
+            manager: Manager::dnf5,
+            options,
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "dnf5 install -y --setopt=install_weak_deps=False --exclude=\"kernel*,mesa*\" --skip-unavailable --no-best htop"
+        );
+
+        temp_dir.close().unwrap();
     }
 
     #[test]
-    fn test_build_module_empty_packages_remotes() {
+    fn test_build_module_options_nogpgcheck_warns() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
         let module = PkgModule {
             name: "test".to_string(),
             r#type: "ostree-pkg".to_string(),
-            manager: Manager::dnf,
-            action: Action::install,
-            on: On::build,
+            packages: vec!["htop".into()],
+            manager: Manager::dnf5,
+            options: Options {
+                nogpgcheck: true,
+                ..Default::default()
+            },
+            cleanup: Some(false),
             ..Default::default()
         };
         let recipe = Recipe {
-            includes_path: "/tmp".to_string(), // Doesn't matter for this test
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "echo \"Warning: GPG signature checks are disabled\" >&2 && dnf5 install -y --no-gpgchecks htop"
+        );
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["app1".into()],
+            manager: Manager::flatpak,
+            options: Options {
+                allow_erasing: true,
+                ..Default::default()
+            },
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(result, "Error: option allow_erasing is not supported on flatpak");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_temporary_remotes_dnf() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module: PkgModule = serde_json::from_str(
+            r#"{
+                "name": "test",
+                "type": "ostree-pkg",
+                "cleanup": false,
+                "packages": ["tailscale", "code"],
+                "manager": "dnf",
+                "temporary_remotes": [
+                    { "name": "tailscale", "url": "https://pkgs.tailscale.com/stable/fedora/tailscale.repo" },
+                    { "name": "vscode", "url": "https://packages.microsoft.com/yumrepos/vscode" },
+                    { "name": "updates-testing" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
+            ..Default::default()
+        };
+        let result = build(module, recipe);
+        assert_eq!(
+            result,
+            "curl -fsSL -o /etc/yum.repos.d/ostree-pkg-tailscale.repo https://pkgs.tailscale.com/stable/fedora/tailscale.repo \
+             && dnf install -y --repofrompath=vscode,https://packages.microsoft.com/yumrepos/vscode --enablerepo=updates-testing tailscale code \
+             && rm -f /etc/yum.repos.d/ostree-pkg-tailscale.repo"
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_build_module_temporary_remotes_flatpak() {
+        let temp_dir = tempdir().unwrap();
+        let includes_path = temp_dir.path().to_str().unwrap().to_string();
+
+        let module = PkgModule {
+            name: "test".to_string(),
+            r#type: "ostree-pkg".to_string(),
+            packages: vec!["org.example.App".into()],
+            manager: Manager::flatpak,
+            temporary_remotes: vec![TemporaryRemote {
+                name: "example".to_string(),
+                url: Some("https://example.org/repo/example.flatpakrepo".to_string()),
+            }],
+            cleanup: Some(false),
+            ..Default::default()
+        };
+        let recipe = Recipe {
+            includes_path: includes_path.clone(),
             ..Default::default()
         };
         let result = build(module, recipe);
-        assert_eq!(result, "dnf install -y  "); // Two spaces at the end are intentional
+        assert_eq!(
+            result,
+            "flatpak remote-add --if-not-exists example https://example.org/repo/example.flatpakrepo \
+             && flatpak install --noninteractive  org.example.App \
+             && flatpak remote-delete --force example"
+        );
+
+        temp_dir.close().unwrap();
     }
 
 
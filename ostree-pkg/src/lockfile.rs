@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::package::{Package, PackageSpec};
+use crate::Manager;
+
+/// An installed package as recorded in a lockfile.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LockEntry {
+    pub package: String,
+    pub version: String,
+    pub release: String,
+    pub arch: String,
+    pub repo: String,
+}
+
+/// Reads a lockfile written by a previous build.
+pub fn read(path: &Path) -> Result<Vec<LockEntry>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Error reading {}: {e}", path.display()))?;
+
+    serde_json::from_str(&contents).map_err(|e| format!("Error parsing {}: {e}", path.display()))
+}
+
+/// Pins an unversioned rpm package to the version and release in `entries`.
+pub fn pin(entries: &[LockEntry], manager: &Manager, package: &Package) -> Package {
+    let name = match (manager, package) {
+        (Manager::dnf | Manager::dnf5, Package::Name(name)) => name,
+        (Manager::dnf | Manager::dnf5, Package::Spec(spec)) if spec.version.is_none() => &spec.name,
+        _ => return package.clone(),
+    };
+
+    match entries.iter().find(|entry| &entry.package == name) {
+        Some(entry) => Package::Spec(PackageSpec {
+            name: name.clone(),
+            version: Some(format!("{}-{}", entry.version, entry.release)),
+            ..Default::default()
+        }),
+        None => package.clone(),
+    }
+}
+
+/// Commands that move installed flatpaks to the commits in `entries`.
+pub fn flatpak_commits(entries: &[LockEntry], packages: &[Package]) -> Vec<String> {
+    packages
+        .iter()
+        .filter_map(|package| {
+            let name = package.uninstall_arg(&Manager::flatpak);
+            entries.iter().find(|entry| entry.package == name)
+        })
+        .map(|entry| {
+            format!(
+                "flatpak update --noninteractive --commit={} {}",
+                entry.release, entry.package
+            )
+        })
+        .collect()
+}
+
+/// Builds a command that writes the installed versions of `names` to `path`
+/// as a JSON array and echoes it into the build output.
+pub fn query(manager: &Manager, names: &[String], path: &str) -> Result<String, String> {
+    let records = match manager {
+        Manager::dnf => format!("dnf repoquery --installed --qf '{RPM_FORMAT}' {}", names.join(" ")),
+        Manager::dnf5 => format!("dnf5 repoquery --installed --qf '{RPM_FORMAT}' {}", names.join(" ")),
+        // flatpak list can't be given refs, so its output is filtered to them
+        Manager::flatpak => format!(
+            "flatpak list --columns=application,version,active,arch,origin \
+             | awk -F'\\t' -v names='{}' '{FLATPAK_NAMES} $1 in keep {FLATPAK_FORMAT}'",
+            names.join(" ")
+        ),
+        _ => return Err("Error: lockfiles are only supported on dnf, dnf5 and flatpak".into()),
+    };
+
+    let dir = Path::new(path).parent().unwrap_or(Path::new("/")).display().to_string();
+
+    Ok(format!("mkdir -p {dir} && {records} | awk '{JSON_ARRAY}' > {path} && cat {path}"))
+}
+
+const RPM_FORMAT: &str = r#"{"package":"%{name}","version":"%{version}","release":"%{release}","arch":"%{arch}","repo":"%{from_repo}"}\n"#;
+
+const FLATPAK_NAMES: &str = r#"BEGIN {n = split(names, list, " "); for (i = 1; i <= n; i++) keep[list[i]] = 1}"#;
+
+const FLATPAK_FORMAT: &str = r#"{printf "{\"package\":\"%s\",\"version\":\"%s\",\"release\":\"%s\",\"arch\":\"%s\",\"repo\":\"%s\"}\n", $1, $2, $3, $4, $5}"#;
+
+const JSON_ARRAY: &str = r#"BEGIN {print "["} NF {printf "%s%s", sep, $0; sep = ",\n"} END {print "\n]"}"#;
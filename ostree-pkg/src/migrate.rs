@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use uuid::Uuid;
+
+use crate::package::Package;
+use crate::state::Record;
+use crate::{Action, As, Manager, On, PkgModule};
+
+/// Flatpaks installed on first boot in place of the packages an
+/// `Action::migrate` module removes from the image.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Migration {
+    #[serde(default)]
+    pub flatpaks: Vec<Package>,
+
+    /// User data copied to where the flatpaks expect it.
+    #[serde(default)]
+    pub data: Vec<DataPath>,
+}
+
+/// A path relative to each user's home, such as `.mozilla`, copied to `to`,
+/// such as `.var/app/org.mozilla.firefox/.mozilla`, unless `to` already exists.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct DataPath {
+    pub from: String,
+    pub to: String,
+}
+
+/// Removes `packages` on build and installs the flatpaks of `migration` on
+/// boot, copying user data over at each user's first login.
+pub fn migrate(module: PkgModule, includes_dir: &Path) -> String {
+    // rpm-ostree's uninstall only removes layered packages, not those of the
+    // base image being migrated away from
+    if !matches!(module.manager, Manager::dnf | Manager::dnf5) {
+        return format!("Error: migrate is not supported on {}", module.manager.name());
+    }
+
+    if module.packages.is_empty() || module.migration.flatpaks.is_empty() {
+        return "Error: migrate needs packages to remove and flatpaks to install".into();
+    }
+
+    let copy = PkgModule {
+        r#as: As::user,
+        runtime_conditions: module.runtime_conditions.clone(),
+        ..Default::default()
+    };
+
+    let data = module
+        .migration
+        .data
+        .iter()
+        .map(|path| {
+            let from = format!("$HOME/{}", path.from.trim_start_matches("~/"));
+            let to = format!("$HOME/{}", path.to.trim_start_matches("~/"));
+
+            format!(
+                "if [ -e \"{from}\" ] && [ ! -e \"{to}\" ]; then mkdir -p \"$(dirname \"{to}\")\" && cp -a \"{from}\" \"{to}\"; fi"
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut steps = Vec::new();
+
+    // each part writes nothing unless it is valid, so stop at the first error
+    for part in [removal(&module), install(&module)] {
+        match crate::run(part, includes_dir) {
+            step if step.starts_with("Error") => return step,
+            step => steps.push(step),
+        }
+    }
+
+    if !data.is_empty() {
+        let uuid = Uuid::new_v4().to_string();
+
+        match crate::boot_service(&copy, includes_dir, &uuid, crate::Exec::Script { env: &[], steps: &data }) {
+            Ok(enable) => steps.push(enable),
+            Err(e) => return e,
+        }
+    }
+
+    steps.join(" && ")
+}
+
+/// The flatpak install, with the module's boot settings but not those that
+/// only apply to the packages being removed.
+fn install(module: &PkgModule) -> PkgModule {
+    PkgModule {
+        packages: module.migration.flatpaks.clone(),
+        manager: Manager::flatpak,
+        action: Action::install,
+        on: On::boot,
+        swaps: Vec::new(),
+        temporary_remotes: Vec::new(),
+        keys: Vec::new(),
+        options: Default::default(),
+        args: Vec::new(),
+        prefix: None,
+        versionlock: false,
+        emit_lockfile: false,
+        lockfile: None,
+        migration: Migration::default(),
+        ..module.clone()
+    }
+}
+
+/// The removal of the packages on build, without the module's boot settings.
+fn removal(module: &PkgModule) -> PkgModule {
+    PkgModule {
+        action: Action::uninstall,
+        on: On::build,
+        prefetch: false,
+        verify: false,
+        retry: None,
+        on_failure: None,
+        notify: false,
+        status: false,
+        plan: false,
+        runtime_conditions: None,
+        users: Vec::new(),
+        user_remotes: Vec::new(),
+        linger: false,
+        network: None,
+        credentials: Vec::new(),
+        migration: Migration::default(),
+        ..module.clone()
+    }
+}
+
+/// The package actions of both the removal and the install.
+pub fn records(module: &PkgModule) -> Vec<Record> {
+    crate::records(&removal(module))
+        .into_iter()
+        .chain(crate::records(&install(module)))
+        .collect()
+}
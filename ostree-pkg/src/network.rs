@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::setup::{Setup, Var};
+use crate::Manager;
+
+/// How an `On::boot` module reaches the network. The proxy and CA bundle are
+/// exported to every manager, and dnf is also given them as options.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Network {
+    /// Proxy URL for http and https.
+    pub proxy: Option<String>,
+
+    /// Hosts and domains reached without the proxy.
+    pub no_proxy: Vec<String>,
+
+    /// Base URLs that replace those of dnf repositories or flatpak remotes,
+    /// by repository or remote name.
+    pub mirrors: HashMap<String, String>,
+
+    /// CA bundle trusted for https.
+    pub ca_bundle: Option<String>,
+}
+
+impl Network {
+    /// Fills settings left unset from the `proxy`, `no_proxy` and
+    /// `ca_bundle` variables.
+    pub fn with_defaults(mut self, vars: &HashMap<String, String>) -> Self {
+        if self.proxy.is_none() {
+            self.proxy = vars.get("proxy").cloned();
+        }
+        if self.no_proxy.is_empty() {
+            self.no_proxy = vars
+                .get("no_proxy")
+                .map(|hosts| hosts.split(',').map(|host| host.trim().to_string()).collect())
+                .unwrap_or_default();
+        }
+        if self.ca_bundle.is_none() {
+            self.ca_bundle = vars.get("ca_bundle").cloned();
+        }
+
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proxy.is_none() && self.no_proxy.is_empty() && self.mirrors.is_empty() && self.ca_bundle.is_none()
+    }
+
+    pub fn settings(&self, manager: &Manager, pkg_mgr: &str) -> Result<Setup, String> {
+        let mut settings = Setup::default();
+
+        if let Some(proxy) = &self.proxy {
+            for var in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+                settings.env.push(Var::value(var, proxy));
+            }
+        }
+        if !self.no_proxy.is_empty() {
+            let hosts = self.no_proxy.join(",");
+            settings.env.push(Var::value("no_proxy", &hosts));
+            settings.env.push(Var::value("NO_PROXY", &hosts));
+        }
+        if let Some(ca_bundle) = &self.ca_bundle {
+            for var in ["SSL_CERT_FILE", "CURL_CA_BUNDLE", "NIX_SSL_CERT_FILE", "PIP_CERT", "NODE_EXTRA_CA_CERTS", "CARGO_HTTP_CAINFO"] {
+                settings.env.push(Var::value(var, ca_bundle));
+            }
+        }
+
+        match manager {
+            Manager::dnf | Manager::dnf5 => {
+                if let Some(proxy) = &self.proxy {
+                    settings.flags.push(format!("--setopt=proxy={proxy}"));
+                }
+                if let Some(ca_bundle) = &self.ca_bundle {
+                    settings.flags.push(format!("--setopt=sslcacert={ca_bundle}"));
+                }
+
+                let mut mirrors = self.mirrors.iter().collect::<Vec<_>>();
+                mirrors.sort();
+
+                for (repo, url) in mirrors {
+                    settings.flags.push(format!("--setopt={repo}.baseurl={url}"));
+                    settings.flags.push(format!("--setopt={repo}.metalink="));
+                    settings.flags.push(format!("--setopt={repo}.mirrorlist="));
+                }
+            }
+            Manager::flatpak => {
+                let mut mirrors = self.mirrors.iter().collect::<Vec<_>>();
+                mirrors.sort();
+
+                for (remote, url) in mirrors {
+                    settings.before.push(format!("{pkg_mgr} remote-modify --url={url} {remote}"));
+                }
+            }
+            _ if !self.mirrors.is_empty() => {
+                return Err(format!("Error: mirrors are not supported on {}", manager.name()));
+            }
+            _ => {}
+        }
+
+        Ok(settings)
+    }
+}
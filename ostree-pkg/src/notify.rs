@@ -0,0 +1,15 @@
+use crate::As;
+
+/// A command that shows a desktop notification. System services have no
+/// session of their own, so they notify every logged-in user instead.
+pub fn send(r#as: &As, summary: &str, body: &str) -> String {
+    let notify_send = format!("notify-send --app-name=ostree-pkg \"{summary}\" \"{body}\"");
+
+    match r#as {
+        As::user => format!("{{ {notify_send} || true; }}"),
+        As::system => format!(
+            "{{ loginctl list-users --no-legend | while read -r _ user _; do \
+             systemd-run --quiet --machine=\"$user@\" --user {notify_send} || true; done; }}"
+        ),
+    }
+}
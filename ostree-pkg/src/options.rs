@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Manager;
+
+/// Common package manager settings, translated into the flags each manager
+/// understands. Anything not covered here can still be passed through `args`.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Options {
+    /// Install weak dependencies (`Recommends:`) alongside packages.
+    #[serde(default)]
+    pub weak_deps: Option<bool>,
+
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    #[serde(default)]
+    pub allow_erasing: bool,
+
+    #[serde(default)]
+    pub skip_unavailable: bool,
+
+    /// Refresh repository metadata before running.
+    #[serde(default)]
+    pub refresh: bool,
+
+    /// Fail unless the newest version of every package can be installed.
+    #[serde(default)]
+    pub best: Option<bool>,
+
+    /// Disable signature checks. A warning is printed whenever this is used.
+    #[serde(default)]
+    pub nogpgcheck: bool,
+}
+
+impl Options {
+    pub fn flags(&self, manager: &Manager) -> Result<Vec<String>, String> {
+        let mut flags = Vec::new();
+
+        match manager {
+            Manager::dnf | Manager::dnf5 => {
+                let dnf5 = matches!(manager, Manager::dnf5);
+
+                if let Some(weak_deps) = self.weak_deps {
+                    let value = if weak_deps { "True" } else { "False" };
+                    flags.push(format!("--setopt=install_weak_deps={value}"));
+                }
+                if !self.exclude.is_empty() {
+                    flags.push(format!("--exclude=\"{}\"", self.exclude.join(",")));
+                }
+                if self.allow_erasing {
+                    flags.push("--allowerasing".into());
+                }
+                if self.skip_unavailable {
+                    flags.push(if dnf5 { "--skip-unavailable" } else { "--setopt=strict=False" }.into());
+                }
+                if self.refresh {
+                    flags.push("--refresh".into());
+                }
+                match (self.best, dnf5) {
+                    (Some(true), _) => flags.push("--best".into()),
+                    (Some(false), true) => flags.push("--no-best".into()),
+                    (Some(false), false) => flags.push("--nobest".into()),
+                    (None, _) => {}
+                }
+                if self.nogpgcheck {
+                    flags.push(if dnf5 { "--no-gpgchecks" } else { "--nogpgcheck" }.into());
+                }
+            }
+
+            _ => self.unsupported(manager)?,
+        }
+
+        Ok(flags)
+    }
+
+    /// A warning to print before running with weakened security.
+    pub fn warning(&self) -> Option<&'static str> {
+        match self.nogpgcheck {
+            true => Some("Warning: GPG signature checks are disabled"),
+            false => None,
+        }
+    }
+
+    fn unsupported(&self, manager: &Manager) -> Result<(), String> {
+        let set = [
+            ("weak_deps", self.weak_deps.is_some()),
+            ("exclude", !self.exclude.is_empty()),
+            ("allow_erasing", self.allow_erasing),
+            ("skip_unavailable", self.skip_unavailable),
+            ("refresh", self.refresh),
+            ("best", self.best.is_some()),
+            ("nogpgcheck", self.nogpgcheck),
+        ];
+
+        match set.iter().find(|(_, set)| *set) {
+            Some((name, _)) => Err(format!("Error: option {name} is not supported on {}", manager.name())),
+            None => Ok(()),
+        }
+    }
+}
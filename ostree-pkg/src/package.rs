@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+
+use crate::when::When;
+use crate::Manager;
+
+/// Comparison applied to a package's `version`.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum Constraint {
+    #[default]
+    eq,
+    ge,
+    gt,
+    le,
+    lt,
+}
+
+impl Constraint {
+    fn operator(&self) -> &'static str {
+        match self {
+            Constraint::eq => "=",
+            Constraint::ge => ">=",
+            Constraint::gt => ">",
+            Constraint::le => "<=",
+            Constraint::lt => "<",
+        }
+    }
+}
+
+/// A package given either as a plain name or as an object carrying a version.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Package {
+    Name(String),
+    Spec(PackageSpec),
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct PackageSpec {
+    pub name: String,
+
+    #[serde(default)]
+    pub version: Option<String>,
+
+    #[serde(default)]
+    pub constraint: Constraint,
+
+    /// Build-time conditions the package is only installed under.
+    #[serde(default)]
+    pub when: Option<When>,
+}
+
+impl From<String> for Package {
+    fn from(name: String) -> Self {
+        Package::Name(name)
+    }
+}
+
+impl From<&str> for Package {
+    fn from(name: &str) -> Self {
+        Package::Name(name.into())
+    }
+}
+
+/// Splits a `name@version` pin, ignoring a leading `@` so npm scopes such as
+/// `@scope/name@1.0.0` keep their name intact.
+fn split_version(package: &str) -> (&str, Option<&str>) {
+    match package.rfind('@') {
+        Some(i) if i > 0 => (&package[..i], Some(&package[i + 1..])),
+        _ => (package, None),
+    }
+}
+
+impl Package {
+    pub fn when(&self) -> Option<&When> {
+        match self {
+            Package::Name(_) => None,
+            Package::Spec(spec) => spec.when.as_ref(),
+        }
+    }
+
+    /// The file of a `path:` package, relative to the includes tree.
+    pub fn local_path(&self) -> Option<&str> {
+        match self {
+            Package::Name(name) => name.strip_prefix("path:"),
+            Package::Spec(_) => None,
+        }
+    }
+
+    /// Name, version and constraint. Plain names only carry a version through
+    /// the `name@version` shorthand of the language-level managers.
+    fn parts(&self, manager: &Manager) -> (&str, Option<&str>, Constraint) {
+        match (self, manager) {
+            (Package::Name(name), Manager::pipx | Manager::cargo | Manager::npm) => {
+                let (name, version) = split_version(name);
+                (name, version, Constraint::eq)
+            }
+            (Package::Name(name), _) => (name, None, Constraint::eq),
+            (Package::Spec(spec), _) => (&spec.name, spec.version.as_deref(), spec.constraint.clone()),
+        }
+    }
+
+    /// Renders the package as an install argument for `manager`.
+    pub fn install_arg(&self, manager: &Manager) -> Result<String, String> {
+        let (name, version, constraint) = self.parts(manager);
+
+        let Some(version) = version else {
+            return Ok(match manager {
+                Manager::nix if !name.contains('#') => format!("nixpkgs#{name}"),
+                _ => name.into(),
+            });
+        };
+
+        let operator = constraint.operator();
+
+        match (manager, constraint) {
+            (Manager::dnf | Manager::dnf5 | Manager::rpm_ostree, Constraint::eq) => Ok(format!("{name}-{version}")),
+            (Manager::dnf | Manager::dnf5 | Manager::rpm_ostree, _) => Ok(format!("\"{name} {operator} {version}\"")),
+            (Manager::pipx, Constraint::eq) => Ok(format!("{name}=={version}")),
+            (Manager::pipx, _) => Ok(format!("\"{name}{operator}{version}\"")),
+            (Manager::cargo | Manager::npm, Constraint::eq) => Ok(format!("{name}@{version}")),
+            (Manager::cargo | Manager::npm, _) => Ok(format!("\"{name}@{operator}{version}\"")),
+            (Manager::flatpak, Constraint::eq) => Ok(format!("{name}//{version}")),
+            (Manager::flatpak, _) => Err(format!("Error: {name} must be pinned to an exact flatpak branch")),
+            (Manager::nix, _) => Err(format!("Error: {name} can't be pinned on nix")),
+        }
+    }
+
+    /// Renders the package as an uninstall argument, which never carries a version.
+    pub fn uninstall_arg(&self, manager: &Manager) -> String {
+        let (name, _, _) = self.parts(manager);
+
+        match manager {
+            Manager::nix => name.rsplit('#').next().unwrap_or(name).into(),
+            _ => name.into(),
+        }
+    }
+
+    /// The dnf versionlock entry for an exactly pinned package.
+    pub fn lock_arg(&self, manager: &Manager) -> Option<String> {
+        match self.parts(manager) {
+            (name, Some(version), Constraint::eq) => Some(format!("{name}-{version}")),
+            _ => None,
+        }
+    }
+}
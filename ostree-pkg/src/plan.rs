@@ -0,0 +1,94 @@
+use serde::Serialize;
+use std::path::Path;
+
+use crate::retry::Retry;
+use crate::setup::Var;
+use crate::{write_include, PkgModule};
+
+const DIR: &str = "usr/share/ostree-pkg/plans.d";
+
+/// What the agent runs for a module besides the manager itself: the flags
+/// and targets it is called with, and the shell commands around it.
+pub struct Invocation {
+    pub flags: Vec<String>,
+    pub targets: Vec<String>,
+    pub env: Vec<Var>,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// A boot module as `ostree-pkg-agent` runs it. The agent skips the plan
+/// unless `conditions` are met, then runs `before` with `sh -c`, the manager
+/// command it builds from `manager`, `action`, `flags` and `targets`, and
+/// `after`, all with `env` set, stopping at the first that fails.
+#[derive(Serialize)]
+struct Plan<'a> {
+    module: &'a str,
+    unit: &'a str,
+    scope: &'static str,
+    manager: &'static str,
+    action: &'static str,
+    packages: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conditions: Option<Conditions<'a>>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    env: &'a [Var],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    before: &'a [String],
+    flags: Vec<String>,
+    targets: Vec<String>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    after: &'a [String],
+    retry: Option<&'a Retry>,
+}
+
+/// PCI vendor ids, one of which a device under `sysfs_root` must have.
+#[derive(Serialize)]
+struct Conditions<'a> {
+    pci_vendor: Vec<String>,
+    sysfs_root: &'a str,
+}
+
+/// `arg` without the quotes the plugin adds for `sh`, as the agent passes
+/// arguments to the manager directly.
+fn unquoted(arg: &str) -> String {
+    arg.replace('"', "")
+}
+
+/// Writes the plan for `unit` into the includes tree, returning the command
+/// its service starts the agent with.
+pub fn write(module: &PkgModule, unit: &str, invocation: &Invocation, includes_dir: &Path) -> Result<String, String> {
+    let plan = Plan {
+        module: &module.name,
+        unit,
+        scope: module.r#as.name(),
+        manager: module.manager.name(),
+        action: module.action.name(),
+        packages: module
+            .packages
+            .iter()
+            .map(|package| package.uninstall_arg(&module.manager))
+            .collect(),
+        conditions: module
+            .runtime_conditions
+            .as_ref()
+            .filter(|conditions| !conditions.pci_vendor.is_empty())
+            .map(|conditions| Conditions {
+                pci_vendor: conditions.vendor_ids(),
+                sysfs_root: conditions.sysfs(),
+            }),
+        env: &invocation.env,
+        before: &invocation.before,
+        flags: invocation.flags.iter().map(|flag| unquoted(flag)).collect(),
+        targets: invocation.targets.iter().map(|target| unquoted(target)).collect(),
+        after: &invocation.after,
+        retry: module.retry.as_ref(),
+    };
+
+    let contents = serde_json::to_string_pretty(&plan).map_err(|e| format!("Error serializing plan: {e}"))?;
+    let path = format!("{DIR}/{unit}.json");
+
+    write_include(&includes_dir.join(&path), &contents)?;
+
+    Ok(format!("/usr/bin/ostree-pkg-agent /{path}"))
+}
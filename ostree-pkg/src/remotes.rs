@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+use crate::setup::Setup;
+use crate::Manager;
+
+/// A repository that is only enabled while the module's build command runs.
+///
+/// For dnf a `url` ending in `.repo` is fetched into `/etc/yum.repos.d/`, any
+/// other `url` is used as the repository's baseurl and a bare `name` enables a
+/// repository that ships disabled. Flatpak remotes need a `url`.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct TemporaryRemote {
+    pub name: String,
+
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+/// Enables `remotes` for a single command, leaving no trace in the image.
+pub fn temporary(manager: &Manager, remotes: &[TemporaryRemote]) -> Result<Setup, String> {
+    let mut temporary = Setup::default();
+
+    for remote in remotes {
+        let name = &remote.name;
+
+        match (manager, &remote.url) {
+            (Manager::dnf | Manager::dnf5, Some(url)) if url.ends_with(".repo") => {
+                let file = format!("/etc/yum.repos.d/ostree-pkg-{name}.repo");
+                temporary.before.push(format!("curl -fsSL -o {file} {url}"));
+                temporary.after.push(format!("rm -f {file}"));
+            }
+            (Manager::dnf | Manager::dnf5, Some(url)) => {
+                temporary.flags.push(format!("--repofrompath={name},{url}"));
+            }
+            (Manager::dnf | Manager::dnf5, None) => {
+                temporary.flags.push(format!("--enablerepo={name}"));
+            }
+            (Manager::flatpak, Some(url)) => {
+                temporary.before.push(format!("flatpak remote-add --if-not-exists {name} {url}"));
+                temporary.after.push(format!("flatpak remote-delete --force {name}"));
+            }
+            (Manager::flatpak, None) => {
+                return Err(format!("Error: temporary remote {name} needs a url"));
+            }
+            _ => {
+                return Err(format!(
+                    "Error: temporary_remotes are not supported on {}",
+                    manager.name()
+                ))
+            }
+        }
+    }
+
+    Ok(temporary)
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+use crate::As;
+
+/// How a failed boot install is retried. Each attempt waits `delay` seconds,
+/// multiplied by `backoff` after every failure, plus up to `jitter` random
+/// seconds. Once `attempts` are used up the service gives up and records it.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retry {
+    pub attempts: u32,
+    pub delay: u64,
+    pub backoff: u64,
+    pub jitter: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 5,
+            delay: 30,
+            backoff: 2,
+            jitter: 0,
+        }
+    }
+}
+
+/// Directory a service that gave up leaves a file named after itself in.
+pub fn failed_dir(r#as: &As) -> &'static str {
+    match r#as {
+        As::system => "/var/lib/ostree-pkg/failed",
+        As::user => "${XDG_STATE_HOME:-$HOME/.local/state}/ostree-pkg/failed",
+    }
+}
+
+impl Retry {
+    /// Rejects a policy that would never run the install.
+    pub fn check(&self) -> Result<(), String> {
+        match self.attempts {
+            0 => Err("Error: retry needs at least one attempt".into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wraps `command` in a loop that retries it, recording in
+    /// [`failed_dir`] when `unit` gives up.
+    pub fn script(&self, command: &str, unit: &str, r#as: &As) -> String {
+        let failed = format!("{}/{unit}", failed_dir(r#as));
+        let sleep = match self.jitter {
+            0 => "sleep \"$delay\"".to_string(),
+            jitter => format!(
+                "sleep $((delay + $(od -An -N4 -tu4 /dev/urandom) % {}))",
+                jitter + 1
+            ),
+        };
+
+        format!(
+            "attempt=1
+delay={delay}
+until {command}; do
+    if [ \"$attempt\" -ge {attempts} ]; then
+        mkdir -p \"$(dirname \"{failed}\")\" && echo \"gave up after $attempt attempts at $(date -Iseconds)\" > \"{failed}\"
+        exit 1
+    fi
+    {sleep}
+    attempt=$((attempt + 1))
+    delay=$((delay * {backoff}))
+done
+rm -f \"{failed}\"",
+            delay = self.delay,
+            attempts = self.attempts,
+            backoff = self.backoff,
+        )
+    }
+}
@@ -0,0 +1,85 @@
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Flags a module's settings add to the manager, variables exported for it,
+/// plus commands to run before and after it.
+#[derive(Default)]
+pub struct Setup {
+    pub flags: Vec<String>,
+
+    /// Variables exported before every command of the module.
+    pub env: Vec<Var>,
+
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+
+    /// Files copied within the includes tree, as source and target, once the
+    /// whole module is known to be valid.
+    pub copies: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Setup {
+    /// Adds the flags and commands of `other` to these, running its commands
+    /// inside any set up so far.
+    pub fn extend(&mut self, other: Setup) {
+        self.flags.extend(other.flags);
+        self.env.extend(other.env);
+        self.before.extend(other.before);
+        self.after.splice(0..0, other.after);
+        self.copies.extend(other.copies);
+    }
+}
+
+/// An environment variable, set to `value` or to the secret in the systemd
+/// credential named `credential`.
+#[derive(Clone, Serialize)]
+pub struct Var {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub source: Source,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Serialize)]
+pub enum Source {
+    value(String),
+    credential(String),
+}
+
+impl Var {
+    pub fn value(name: &str, value: &str) -> Var {
+        Var {
+            name: name.into(),
+            source: Source::value(value.into()),
+        }
+    }
+
+    pub fn credential(name: &str, credential: &str) -> Var {
+        Var {
+            name: name.into(),
+            source: Source::credential(credential.into()),
+        }
+    }
+
+    /// The variable as a shell assignment.
+    fn shell(&self) -> String {
+        match &self.source {
+            Source::value(value) => format!("{}=\"{value}\"", self.name),
+            Source::credential(credential) => {
+                format!("{}=\"$(cat \"$CREDENTIALS_DIRECTORY/{credential}\")\"", self.name)
+            }
+        }
+    }
+}
+
+/// `commands` preceded by the export of `env`, if any.
+pub fn exported(env: &[Var], commands: Vec<String>) -> Vec<String> {
+    match env.is_empty() {
+        true => commands,
+        false => {
+            let vars = env.iter().map(Var::shell).collect::<Vec<_>>();
+            std::iter::once(format!("export {}", vars.join(" "))).chain(commands).collect()
+        }
+    }
+}
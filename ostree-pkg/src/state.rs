@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// How a module that contradicts or repeats an earlier one is treated.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum Conflicts {
+    #[default]
+    warn,
+    error,
+    ignore,
+}
+
+/// A package action taken by a module.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub module: String,
+    pub manager: String,
+    pub scope: String,
+    pub action: String,
+    pub package: String,
+}
+
+/// Every package action of the current build. A state left behind by
+/// another build is discarded.
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    build: String,
+    records: Vec<Record>,
+}
+
+/// Identifies the current build. vib loads the plugin into its own process
+/// and builds one recipe per run, so the build is that process, told apart
+/// from an earlier one with the same pid by its start time.
+fn build_id() -> String {
+    let pid = std::process::id();
+
+    // the start time is the 22nd field of /proc/self/stat, counted after the
+    // command name, which is in parentheses and may contain spaces
+    let start = fs::read_to_string("/proc/self/stat").ok().and_then(|stat| {
+        stat.rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().nth(19).map(String::from))
+    });
+
+    match start {
+        Some(start) => format!("{pid}-{start}"),
+        None => pid.to_string(),
+    }
+}
+
+/// Actions that undo each other.
+fn opposite(action: &str) -> Option<&'static str> {
+    match action {
+        "install" => Some("uninstall"),
+        "uninstall" => Some("install"),
+        "group_install" => Some("group_remove"),
+        "group_remove" => Some("group_install"),
+        _ => None,
+    }
+}
+
+/// Reads the state of the current build from the file at `path`.
+fn load(path: &Path) -> Result<State, String> {
+    let build = build_id();
+
+    let state = match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str::<State>(&contents)
+            .map_err(|e| format!("Error parsing {}: {e}", path.display()))?,
+        Err(_) => State::default(),
+    };
+
+    match state.build == build {
+        true => Ok(state),
+        false => Ok(State {
+            build,
+            records: Vec::new(),
+        }),
+    }
+}
+
+/// Returns a message for every record in the state file at `path` that
+/// `records` contradict or duplicate.
+pub fn check(path: &Path, records: &[Record]) -> Result<Vec<String>, String> {
+    if records.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let state = load(path)?;
+    let mut messages = Vec::new();
+
+    for record in records {
+        for earlier in &state.records {
+            if earlier.manager != record.manager
+                || earlier.scope != record.scope
+                || earlier.package != record.package
+            {
+                continue;
+            }
+
+            if earlier.action == record.action {
+                messages.push(format!(
+                    "{} in module {} duplicates module {}",
+                    record.package, record.module, earlier.module
+                ));
+            } else if opposite(&earlier.action) == Some(record.action.as_str()) {
+                messages.push(format!(
+                    "{} {} in module {} contradicts {} in module {}",
+                    record.action, record.package, record.module, earlier.action, earlier.module
+                ));
+            }
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Adds `records` to the state file at `path`, once their module succeeded.
+pub fn save(path: &Path, records: Vec<Record>) -> Result<(), String> {
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let mut state = load(path)?;
+    state.records.extend(records);
+
+    let contents = serde_json::to_string_pretty(&state)
+        .map_err(|e| format!("Error serializing {}: {e}", path.display()))?;
+    crate::write_include(path, &contents)
+}
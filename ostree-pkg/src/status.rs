@@ -0,0 +1,44 @@
+use crate::{As, PkgModule};
+
+/// Directory boot services record the outcome of their last run in, one
+/// `<unit>.json` per service.
+pub fn dir(r#as: &As) -> &'static str {
+    match r#as {
+        As::system => "/var/lib/ostree-pkg/status",
+        As::user => "${XDG_STATE_HOME:-$HOME/.local/state}/ostree-pkg/status",
+    }
+}
+
+/// Shell that records the module, the exit code of the script and the number
+/// of attempts a retry policy took once the script of `unit` exits.
+pub fn script(module: &PkgModule, unit: &str) -> String {
+    let record = serde_json::json!({
+        "module": module.name,
+        "unit": unit,
+        "scope": module.r#as.name(),
+        "manager": module.manager.name(),
+        "action": module.action.name(),
+        "packages": module
+            .packages
+            .iter()
+            .map(|package| package.uninstall_arg(&module.manager))
+            .collect::<Vec<_>>(),
+    })
+    .to_string();
+
+    let record = format!(
+        "{},\"time\":\"$(date -Iseconds)\",\"exit_code\":$code,\"attempts\":${{attempt:-1}}}}",
+        record.trim_end_matches('}')
+    );
+    let dir = dir(&module.r#as);
+
+    format!(
+        "ostree_pkg_status() {{
+    code=$?
+    mkdir -p \"{dir}\" && cat > \"{dir}/{unit}.json\" <<EOF
+{record}
+EOF
+}}
+trap ostree_pkg_status EXIT"
+    )
+}
@@ -0,0 +1,27 @@
+use serde::{Deserialize, Serialize};
+
+/// A remote added to each user's installation before installing for them.
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct UserRemote {
+    pub name: String,
+    pub url: String,
+}
+
+/// Runs `command` from a system service as each of `users`, so their apps are
+/// installed before they first log in. Lingering starts a user's service
+/// manager at boot instead of at their first login.
+pub fn as_users(users: &[String], linger: bool, command: &str) -> Vec<String> {
+    let command = command.replace('\'', r"'\''");
+
+    users
+        .iter()
+        .flat_map(|user| {
+            let linger = match linger {
+                true => Some(format!("loginctl enable-linger {user}")),
+                false => None,
+            };
+
+            linger.into_iter().chain(Some(format!("runuser -l {user} -c '{command}'")))
+        })
+        .collect()
+}
@@ -0,0 +1,26 @@
+use crate::Manager;
+
+/// Builds a command that fails the build unless every package in `packages`,
+/// given as install argument and name, can be resolved from the repositories
+/// or remotes configured in the image.
+pub fn verify(manager: &Manager, packages: &[(String, String)]) -> Result<String, String> {
+    let checks = packages.iter().map(|(package, name)| {
+        let query = match manager {
+            Manager::dnf | Manager::dnf5 => {
+                format!("[ -n \"$({} repoquery --quiet {package})\" ]", manager.name())
+            }
+            // the loop runs in the pipeline's subshell, so exit only ends the check
+            _ => format!(
+                "flatpak remotes --columns=name | {{ while read -r remote; do \
+                 flatpak remote-info \"$remote\" {package} >/dev/null 2>&1 && exit 0; done; exit 1; }}"
+            ),
+        };
+
+        format!("{{ {query} || {{ echo \"Error: {name} is not available\" >&2; false; }}; }}")
+    });
+
+    match manager {
+        Manager::dnf | Manager::dnf5 | Manager::flatpak => Ok(checks.collect::<Vec<_>>().join(" && ")),
+        _ => Err(format!("Error: verify is not supported on {}", manager.name())),
+    }
+}
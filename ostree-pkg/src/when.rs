@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::package::Package;
+use crate::PkgModule;
+
+/// Build-time conditions on a module or package, checked against the
+/// module's `vars`. Each condition that is set must match, and a list matches
+/// if any of its values does.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct When {
+    /// Architectures such as `x86_64` or `aarch64`, matched against the `arch`
+    /// variable.
+    pub arch: Vec<String>,
+
+    /// Variables and the value each must have.
+    pub vars: HashMap<String, String>,
+}
+
+impl When {
+    /// Whether the conditions match `vars`. The plugin runs on the build
+    /// host, whose architecture is not the image's in a cross build, so arch
+    /// conditions without an `arch` variable fail instead of guessing.
+    pub fn matches(&self, vars: &HashMap<String, String>) -> Result<bool, String> {
+        let arch = match (self.arch.is_empty(), vars.get("arch")) {
+            (true, _) => true,
+            (false, Some(arch)) => self.arch.contains(arch),
+            (false, None) => return Err("Error: arch conditions need an arch variable".into()),
+        };
+
+        Ok(arch && self.vars.iter().all(|(var, value)| vars.get(var) == Some(value)))
+    }
+}
+
+/// Whether `when`, if set, matches `vars`.
+fn matches(when: Option<&When>, vars: &HashMap<String, String>) -> Result<bool, String> {
+    when.map_or(Ok(true), |when| when.matches(vars))
+}
+
+/// Keeps the packages whose conditions match `vars`.
+fn retain(packages: Vec<Package>, vars: &HashMap<String, String>) -> Result<Vec<Package>, String> {
+    let mut kept = Vec::new();
+
+    for package in packages {
+        if matches(package.when(), vars)? {
+            kept.push(package);
+        }
+    }
+
+    Ok(kept)
+}
+
+/// Drops the packages of `module` whose conditions don't match, returning
+/// `None` if the module's own conditions don't, or no package is left of a
+/// module that had some.
+pub fn filter(mut module: PkgModule) -> Result<Option<PkgModule>, String> {
+    if !matches(module.when.as_ref(), &module.vars)? {
+        return Ok(None);
+    }
+
+    let had_packages = !module.packages.is_empty();
+
+    module.packages = retain(module.packages, &module.vars)?;
+    module.migration.flatpaks = retain(module.migration.flatpaks, &module.vars)?;
+
+    match had_packages && module.packages.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(module)),
+    }
+}